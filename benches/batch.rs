@@ -0,0 +1,39 @@
+//! Compares `ScopedKey::batch` with updating a scoped counter through `with`.
+//!
+//! Run with `cargo bench --bench batch`.
+
+#[macro_use]
+extern crate scoped_tls;
+
+use std::{cell::Cell, time::Instant};
+
+scoped_thread_local!(static BYTES: Cell<u64>);
+
+fn time(name: &str, chunks: &[u64], f: impl FnOnce()) {
+    let total = Cell::new(0);
+    let start = Instant::now();
+    BYTES.set(&total, f);
+    let elapsed = start.elapsed();
+    assert_eq!(total.get(), chunks.iter().sum::<u64>());
+    println!(
+        "{:<8} {:>8.2} ns/update",
+        name,
+        elapsed.as_nanos() as f64 / chunks.len() as f64
+    );
+}
+
+fn main() {
+    let chunks: Vec<u64> = (0..10_000_000).map(|i| i % 4096).collect();
+    time("with", &chunks, || {
+        for chunk in &chunks {
+            BYTES.with(|bytes| bytes.set(bytes.get() + chunk));
+        }
+    });
+    time("batch", &chunks, || {
+        BYTES.batch(|bytes| {
+            for chunk in &chunks {
+                *bytes += chunk;
+            }
+        })
+    });
+}
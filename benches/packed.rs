@@ -0,0 +1,58 @@
+//! Compares a `CopyScopedKey` with a plain `Cell<Option<T>>` thread local,
+//! and with a `ScopedKey` holding a reference to the value.
+//!
+//! Each iteration sets a `u8` flag and reads it back inside the scope, the
+//! way a parser toggles a mode around a nested construct.
+//!
+//! Run with `cargo bench --bench packed`.
+
+#[macro_use]
+extern crate scoped_tls;
+
+use std::{cell::Cell, time::Instant};
+
+scoped_thread_local!(static copy PACKED: u8);
+scoped_thread_local!(static BY_REF: u8);
+thread_local!(static BASELINE: Cell<Option<u8>> = const { Cell::new(None) });
+
+const ITERS: u64 = 10_000_000;
+
+fn time(name: &str, f: impl Fn(u8) -> u8) {
+    let start = Instant::now();
+    let mut sum = 0u64;
+    for i in 0..ITERS {
+        sum += u64::from(f(i as u8));
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(sum, (0..ITERS).map(|i| i % 256).sum::<u64>());
+    println!(
+        "{:<10} {:>8.2} ns/iter",
+        name,
+        elapsed.as_nanos() as f64 / ITERS as f64
+    );
+}
+
+/// `CopyScopedKey::set` on top of the baseline, restoring the previous value
+/// even if `f` panics.
+fn baseline_set<R>(v: u8, f: impl FnOnce() -> R) -> R {
+    struct Reset(Option<u8>);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            let _ = BASELINE.try_with(|c| c.set(self.0));
+        }
+    }
+    let _reset = Reset(BASELINE.with(|c| c.replace(Some(v))));
+    f()
+}
+
+fn baseline_get() -> u8 {
+    BASELINE
+        .with(Cell::get)
+        .expect("cannot access a scoped thread local variable without calling `set` first")
+}
+
+fn main() {
+    time("baseline", |v| baseline_set(v, baseline_get));
+    time("packed", |v| PACKED.set(v, || PACKED.get()));
+    time("by-ref", |v| BY_REF.set(&v, || BY_REF.get()));
+}
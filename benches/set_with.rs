@@ -0,0 +1,27 @@
+//! Measures the hot path of a scoped key: entering a scope and reading the
+//! value back inside it.
+//!
+//! Run with `cargo bench --bench set_with`.
+
+#[macro_use]
+extern crate scoped_tls;
+
+use std::time::Instant;
+
+scoped_thread_local!(static FLAG: u32);
+
+const ITERS: u32 = 10_000_000;
+
+fn main() {
+    let start = Instant::now();
+    let mut sum = 0u64;
+    for i in 0..ITERS {
+        sum += u64::from(FLAG.set(&i, || FLAG.with(|v| *v)));
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(sum, (0..u64::from(ITERS)).sum::<u64>());
+    println!(
+        "set+with {:>8.2} ns/iter",
+        elapsed.as_nanos() as f64 / f64::from(ITERS)
+    );
+}
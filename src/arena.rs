@@ -0,0 +1,106 @@
+//! Support for keys holding the current arena allocator.
+
+use crate::ScopedKey;
+
+/// An allocator which can hand out references to values moved into it.
+///
+/// This is the interface [`ScopedKey::alloc_in_scope`] needs from the arena
+/// installed in a key. Whether allocated values are ever dropped is up to
+/// the implementation; most bump allocators don't bother.
+pub trait ScopedAlloc {
+    /// Moves `value` into the allocator, returning a reference to it which
+    /// stays valid for as long as the allocator itself.
+    fn alloc<U>(&self, value: U) -> &U;
+}
+
+impl<T: ScopedAlloc> ScopedKey<T> {
+    /// Allocates `value` in the arena currently installed in this key.
+    ///
+    /// # Safety
+    ///
+    /// The lifetime `'a` is unbounded: the returned reference is only valid
+    /// until the `set` of the arena it was allocated in returns, and must not
+    /// be used after that.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use scoped_tls::ScopedAlloc;
+    ///
+    /// struct Leak;
+    ///
+    /// impl ScopedAlloc for Leak {
+    ///     fn alloc<U>(&self, value: U) -> &U {
+    ///         Box::leak(Box::new(value))
+    ///     }
+    /// }
+    ///
+    /// scoped_thread_local!(static ARENA: Leak);
+    ///
+    /// # fn main() {
+    /// ARENA.set(&Leak, || {
+    ///     let name = unsafe { ARENA.alloc_in_scope(String::from("node")) };
+    ///     assert_eq!(name, "node");
+    /// });
+    /// # }
+    /// ```
+    pub unsafe fn alloc_in_scope<'a, U>(&'static self, value: U) -> &'a U {
+        self.with(|arena| &*(arena.alloc(value) as *const U))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, UnsafeCell},
+        mem,
+    };
+
+    use crate::ScopedAlloc;
+
+    struct Bump {
+        buf: UnsafeCell<[u64; 8]>,
+        used: Cell<usize>,
+    }
+
+    impl ScopedAlloc for Bump {
+        fn alloc<U>(&self, value: U) -> &U {
+            let base = self.buf.get() as *mut u8;
+            let align = mem::align_of::<U>();
+            let misalignment = (base as usize + self.used.get()) % align;
+            let start = self.used.get() + (align - misalignment) % align;
+            let end = start + mem::size_of::<U>();
+            assert!(end <= mem::size_of::<[u64; 8]>(), "arena is full");
+            self.used.set(end);
+            unsafe {
+                let ptr = base.add(start) as *mut U;
+                ptr.write(value);
+                &*ptr
+            }
+        }
+    }
+
+    #[test]
+    fn bump() {
+        scoped_thread_local!(static ARENA: Bump);
+
+        let arena = Bump {
+            buf: UnsafeCell::new([0; 8]),
+            used: Cell::new(0),
+        };
+        ARENA.set(&arena, || {
+            let a = unsafe { ARENA.alloc_in_scope(1u8) };
+            let b = unsafe { ARENA.alloc_in_scope(2u32) };
+            assert_eq!((*a, *b), (1, 2));
+            assert_eq!(b as *const u32 as usize % mem::align_of::<u32>(), 0);
+        });
+        assert_eq!(arena.used.get(), 8);
+    }
+}
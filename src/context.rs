@@ -0,0 +1,229 @@
+//! A scoped map of string tags, built up incrementally by nested scopes.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    marker,
+};
+
+use crate::ScopedKey;
+
+/// The type of key a [`ContextMap`] stores its entries in.
+pub type ContextKey = ScopedKey<RefCell<HashMap<String, String>>>;
+
+/// The entries shadowed by live guards, keyed by the map's key and the
+/// entry, oldest guard first.
+type Shadowed = HashMap<(usize, String), Vec<(u64, Option<String>)>>;
+
+thread_local!(static SHADOWED: RefCell<Shadowed> = RefCell::new(HashMap::new()));
+thread_local!(static NEXT_GUARD: Cell<u64> = const { Cell::new(0) });
+
+/// Key/value context, like tags for the current request, to which nested
+/// scopes can add entries.
+///
+/// The map itself lives in a scoped key, which has to be `set` before
+/// entries can be inserted.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// use std::{cell::RefCell, collections::HashMap};
+///
+/// use scoped_tls::ContextMap;
+///
+/// scoped_thread_local!(static TAGS: RefCell<HashMap<String, String>>);
+/// static CONTEXT: ContextMap = ContextMap::new(&TAGS);
+///
+/// # fn main() {
+/// TAGS.set(&RefCell::default(), || {
+///     let _user = CONTEXT.insert_scoped("user", "alice");
+///     {
+///         let _step = CONTEXT.insert_scoped("step", "auth");
+///         assert_eq!(CONTEXT.get("step").as_deref(), Some("auth"));
+///     }
+///     assert_eq!(CONTEXT.get("step"), None);
+///     assert_eq!(CONTEXT.get("user").as_deref(), Some("alice"));
+/// });
+/// # }
+/// ```
+pub struct ContextMap {
+    key: &'static ContextKey,
+}
+
+impl ContextMap {
+    /// Creates a context map storing its entries in `key`.
+    pub const fn new(key: &'static ContextKey) -> ContextMap {
+        ContextMap { key }
+    }
+
+    /// Inserts an entry until the returned guard is dropped.
+    ///
+    /// If the map already has an entry for `k`, it is shadowed and comes
+    /// back once the guard is dropped. Guards for the same entry may be
+    /// dropped in any order: the entry keeps the value of the newest guard
+    /// still alive, or the value it had before the oldest one otherwise.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the key holding the map has not been
+    /// `set`, or if it is being modified by an enclosing call.
+    pub fn insert_scoped(&self, k: impl Into<String>, v: impl Into<String>) -> ContextGuard {
+        let k = k.into();
+        let mut prev = self
+            .key
+            .with(|map| map.borrow_mut().insert(k.clone(), v.into()));
+        let id = NEXT_GUARD.with(|next| {
+            next.set(next.get() + 1);
+            next.get()
+        });
+        let slot = (key_addr(self.key), k.clone());
+        // During thread teardown the guard restores `prev` itself.
+        let _ = SHADOWED.try_with(|shadowed| {
+            let prev = prev.take();
+            shadowed
+                .borrow_mut()
+                .entry(slot)
+                .or_default()
+                .push((id, prev));
+        });
+        ContextGuard {
+            key: self.key,
+            entry: k,
+            id,
+            prev,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Returns a copy of the entry for `k`, or `None` if there is none or the
+    /// key holding the map is not set.
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.key
+            .try_with(|map| map.borrow().get(k).cloned())
+            .ok()
+            .and_then(|v| v)
+    }
+}
+
+/// Removes an entry from a [`ContextMap`] when dropped, see
+/// [`ContextMap::insert_scoped`].
+///
+/// The guard has to be dropped while the map it inserted into is still
+/// installed, or it will affect whichever map is installed at the time.
+#[must_use = "the entry is removed again as soon as the guard is dropped"]
+pub struct ContextGuard {
+    key: &'static ContextKey,
+    entry: String,
+    id: u64,
+    // Only used if the guard couldn't be tracked in `SHADOWED`.
+    prev: Option<String>,
+    // The map belongs to the thread the guard was created on.
+    _marker: marker::PhantomData<*const ()>,
+}
+
+fn key_addr(key: &'static ContextKey) -> usize {
+    key as *const ContextKey as usize
+}
+
+impl ContextGuard {
+    /// Stops tracking this guard, returning the value to restore the entry
+    /// to, or `None` if a newer guard for the same entry is still alive.
+    fn untrack(&mut self) -> Option<Option<String>> {
+        let slot = (key_addr(self.key), self.entry.clone());
+        let id = self.id;
+        let tracked = SHADOWED
+            .try_with(|shadowed| {
+                let mut shadowed = shadowed.borrow_mut();
+                let stack = shadowed.get_mut(&slot)?;
+                let pos = stack.iter().position(|&(other, _)| other == id)?;
+                let (_, prev) = stack.remove(pos);
+                let restore = match stack.get_mut(pos) {
+                    // The newer guard now restores what this one shadowed.
+                    Some(newer) => {
+                        newer.1 = prev;
+                        None
+                    }
+                    None => Some(prev),
+                };
+                if stack.is_empty() {
+                    shadowed.remove(&slot);
+                }
+                Some(restore)
+            })
+            .ok()
+            .flatten();
+        tracked.unwrap_or_else(|| Some(self.prev.take()))
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let prev = match self.untrack() {
+            Some(prev) => prev,
+            None => return,
+        };
+        let entry = &self.entry;
+        let _ = self.key.try_with(|map| {
+            let mut map = map.borrow_mut();
+            match prev {
+                Some(prev) => map.insert(entry.clone(), prev),
+                None => map.remove(entry),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use crate::ContextMap;
+
+    scoped_thread_local!(static TAGS: RefCell<HashMap<String, String>>);
+    static CONTEXT: ContextMap = ContextMap::new(&TAGS);
+
+    #[test]
+    fn nested_tags() {
+        assert_eq!(CONTEXT.get("a"), None);
+        TAGS.set(&RefCell::default(), || {
+            let a = CONTEXT.insert_scoped("a", "1");
+            {
+                let _b = CONTEXT.insert_scoped("b", "2");
+                let _a = CONTEXT.insert_scoped("a", "shadowed");
+                assert_eq!(CONTEXT.get("a").as_deref(), Some("shadowed"));
+                assert_eq!(CONTEXT.get("b").as_deref(), Some("2"));
+            }
+            assert_eq!(CONTEXT.get("a").as_deref(), Some("1"));
+            assert_eq!(CONTEXT.get("b"), None);
+            drop(a);
+            TAGS.with(|map| assert!(map.borrow().is_empty()));
+        });
+    }
+
+    #[test]
+    fn out_of_order_drop() {
+        TAGS.set(&RefCell::default(), || {
+            let outer = CONTEXT.insert_scoped("a", "1");
+            let middle = CONTEXT.insert_scoped("a", "2");
+            let inner = CONTEXT.insert_scoped("a", "3");
+            drop(outer);
+            assert_eq!(CONTEXT.get("a").as_deref(), Some("3"));
+            drop(inner);
+            assert_eq!(CONTEXT.get("a").as_deref(), Some("2"));
+            drop(middle);
+            assert_eq!(CONTEXT.get("a"), None);
+
+            let _base = CONTEXT.insert_scoped("b", "base");
+            let first = CONTEXT.insert_scoped("b", "1");
+            let second = CONTEXT.insert_scoped("b", "2");
+            drop(first);
+            assert_eq!(CONTEXT.get("b").as_deref(), Some("2"));
+            drop(second);
+            assert_eq!(CONTEXT.get("b").as_deref(), Some("base"));
+        });
+        super::SHADOWED.with(|shadowed| assert!(shadowed.borrow().is_empty()));
+    }
+}
@@ -0,0 +1,75 @@
+//! Tracking which thread each key was last set on, to explain accesses from
+//! threads where it isn't set.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    thread::{self, ThreadId},
+};
+
+use crate::{lazy::Lazy, ScopedKey};
+
+/// The thread each key was last set on, by the key's address.
+static LAST_SET: Lazy<Mutex<HashMap<usize, ThreadId>>> = Lazy::new();
+
+fn last_set() -> &'static Mutex<HashMap<usize, ThreadId>> {
+    LAST_SET.get(Default::default)
+}
+
+fn addr<T: ?Sized>(key: &'static ScopedKey<T>) -> usize {
+    key as *const ScopedKey<T> as *const () as usize
+}
+
+pub(crate) fn entered<T: ?Sized>(key: &'static ScopedKey<T>) {
+    last_set()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(addr(key), thread::current().id());
+}
+
+/// Returns a note to add to the message about accessing `key` while it is
+/// unset, if it was last set on another thread.
+pub(crate) fn hint<T: ?Sized>(key: &'static ScopedKey<T>) -> String {
+    let last = last_set()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&addr(key))
+        .cloned();
+    match last {
+        Some(id) if id != thread::current().id() => format!(
+            " (it was last set on thread {:?}, but scoped values aren't shared between threads)",
+            id
+        ),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{panic, thread};
+
+    #[test]
+    fn hint() {
+        scoped_thread_local!(static FOO: u32);
+
+        let other = thread::spawn(|| {
+            FOO.set(&1, || {});
+            thread::current().id()
+        })
+        .join()
+        .unwrap();
+        let err = panic::catch_unwind(|| FOO.with(|_| ())).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(
+            msg.contains(&format!("last set on thread {:?}", other)),
+            "{}",
+            msg
+        );
+
+        // No hint once it has been set on this thread.
+        FOO.set(&1, || {});
+        let err = panic::catch_unwind(|| FOO.with(|_| ())).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(!msg.contains("last set"), "{}", msg);
+    }
+}
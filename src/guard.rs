@@ -0,0 +1,645 @@
+//! RAII guards over the scopes of a `ScopedKey`.
+
+use std::{
+    marker, mem,
+    ptr::{self, NonNull},
+};
+
+#[cfg(debug_assertions)]
+use std::cell::Cell;
+
+use crate::{Frame, ScopedKey};
+
+/// A heap allocation owned through a raw pointer.
+///
+/// Frames linked into a key are accessed through pointers stored in the
+/// thread local. A `Box` asserts unique access whenever it is moved, which
+/// would invalidate those pointers, so guards hold their frames like this
+/// instead.
+pub(crate) struct Heap<T>(NonNull<T>);
+
+impl<T> Heap<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Heap(NonNull::from(Box::leak(Box::new(value))))
+    }
+
+    pub(crate) fn ptr(&self) -> NonNull<T> {
+        self.0
+    }
+
+    pub(crate) fn get(&self) -> &T {
+        // Safety: the allocation lives until `self` is dropped
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl<T> Drop for Heap<T> {
+    fn drop(&mut self) {
+        // Safety: the pointer came from `Box::leak` and is dropped only once
+        unsafe { drop(Box::from_raw(self.0.as_ptr())) }
+    }
+}
+
+/// A guard which keeps a value installed in a [`ScopedKey`] until it is
+/// dropped.
+///
+/// Created by [`ScopedKey::set_guard`].
+pub struct ScopeGuard<'a, T: ?Sized + 'static> {
+    key: &'static ScopedKey<T>,
+    frame: Heap<Frame<T>>,
+    // The frame borrows the value and is linked into this thread's list of
+    // scopes.
+    _marker: marker::PhantomData<(&'a T, *const ())>,
+}
+
+#[cfg(debug_assertions)]
+thread_local!(static LIVE_GUARDS: Cell<usize> = const { Cell::new(0) });
+
+impl<'a, T: ?Sized> ScopeGuard<'a, T> {
+    /// # Safety
+    /// See `ScopedKey::set_guard`.
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub(crate) unsafe fn new(key: &'static ScopedKey<T>, t: &'a T) -> Self {
+        key.check_set();
+        let frame = Heap::new(Frame::new_here(Some(NonNull::from(t))));
+        // Safety: the frame is on the heap, so it never moves, and the guard
+        // is neither `Send` nor (per the caller) leaked.
+        key.link(frame.get());
+        key.entered();
+        #[cfg(debug_assertions)]
+        LIVE_GUARDS.with(|n| n.set(n.get() + 1));
+        ScopeGuard {
+            key,
+            frame,
+            _marker: marker::PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for ScopeGuard<'_, T> {
+    fn drop(&mut self) {
+        self.key.exit(self.frame.get());
+        #[cfg(debug_assertions)]
+        LIVE_GUARDS.with(|n| n.set(n.get() - 1));
+    }
+}
+
+/// Returns how many [`ScopeGuard`]s have been created on this thread but not
+/// yet dropped.
+///
+/// Tests can check this at a point where all guards should be gone to catch
+/// guards which were leaked with `mem::forget`. Only available with debug
+/// assertions enabled.
+#[cfg(debug_assertions)]
+pub fn leaked_guards() -> usize {
+    LIVE_GUARDS.with(|n| n.get())
+}
+
+/// Two guards combined into one, which drops them in reverse order.
+///
+/// Created by [`combine_guards`].
+pub struct CombinedGuard<'a> {
+    // Fields are dropped in declaration order.
+    _second: Box<dyn Erased + 'a>,
+    _first: Box<dyn Erased + 'a>,
+}
+
+trait Erased {}
+
+impl<T: ?Sized> Erased for T {}
+
+/// Combines two guards into one, so that a function can return multiple
+/// installed scopes as a single value.
+///
+/// When the combined guard is dropped, `b` is dropped before `a`, the same
+/// order as if both had been held as local variables.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// use scoped_tls::CombinedGuard;
+///
+/// scoped_thread_local!(static USER: str);
+/// scoped_thread_local!(static REQUEST: u64);
+///
+/// fn enter_request(user: &'static str, id: &'static u64) -> CombinedGuard<'static> {
+///     // Safety: the guards borrow `'static` values
+///     unsafe { scoped_tls::combine_guards(USER.set_guard(user), REQUEST.set_guard(id)) }
+/// }
+///
+/// # fn main() {
+/// let guard = enter_request("alice", &7);
+/// assert_eq!(REQUEST.get(), 7);
+/// drop(guard);
+/// assert!(!USER.is_set() && !REQUEST.is_set());
+/// # }
+/// ```
+pub fn combine_guards<'a, A: ?Sized, B: ?Sized>(
+    a: ScopeGuard<'a, A>,
+    b: ScopeGuard<'a, B>,
+) -> CombinedGuard<'a> {
+    CombinedGuard {
+        _second: Box::new(b),
+        _first: Box::new(a),
+    }
+}
+
+/// A group of guards whose drop order can be chosen.
+///
+/// Dropping the group drops its guards in reverse order of `push`, like
+/// local variables. [`drop_in_order`](GuardGroup::drop_in_order) drops them
+/// in the order they were pushed instead.
+///
+/// Restoring out of order is sound, but for guards of the same key it means
+/// the key doesn't go back through its previous values: once the outer guard
+/// is dropped, the key still holds the inner value, and only after dropping
+/// the inner guard does it return to what it held before the outer one.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// use scoped_tls::GuardGroup;
+///
+/// scoped_thread_local!(static FOO: u32);
+///
+/// # fn main() {
+/// let mut group = GuardGroup::new();
+/// group.push(unsafe { FOO.set_guard(&1) });
+/// group.push(unsafe { FOO.set_guard(&2) });
+/// group.drop_in_order();
+/// assert!(!FOO.is_set());
+/// # }
+/// ```
+#[derive(Default)]
+pub struct GuardGroup<'a> {
+    guards: Vec<Box<dyn Erased + 'a>>,
+}
+
+impl<'a> GuardGroup<'a> {
+    /// Creates an empty group.
+    pub fn new() -> Self {
+        GuardGroup { guards: Vec::new() }
+    }
+
+    /// Adds `guard` to the group.
+    pub fn push<G: 'a>(&mut self, guard: G) {
+        self.guards.push(Box::new(guard));
+    }
+
+    /// Returns how many guards are in the group.
+    pub fn len(&self) -> usize {
+        self.guards.len()
+    }
+
+    /// Returns whether the group has no guards.
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    /// Drops the guards in the order they were pushed.
+    pub fn drop_in_order(mut self) {
+        // `Vec` drops its elements front to back.
+        drop(mem::take(&mut self.guards));
+    }
+}
+
+impl Drop for GuardGroup<'_> {
+    fn drop(&mut self) {
+        while self.guards.pop().is_some() {}
+    }
+}
+
+/// A stack of values installed in the same [`ScopedKey`], pushed and popped
+/// one at a time.
+///
+/// Created by [`ScopedKey::guard_stack`]. Any values still installed are
+/// removed, innermost first, when the stack is dropped.
+pub struct GuardStack<'a, T: ?Sized + 'static> {
+    key: &'static ScopedKey<T>,
+    guards: Vec<ScopeGuard<'a, T>>,
+}
+
+impl<'a, T: ?Sized> GuardStack<'a, T> {
+    /// # Safety
+    /// See `ScopedKey::guard_stack`.
+    pub(crate) unsafe fn new(key: &'static ScopedKey<T>) -> Self {
+        GuardStack {
+            key,
+            guards: Vec::new(),
+        }
+    }
+
+    /// Installs `t` as the new innermost value of the key.
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn push(&mut self, t: &'a T) {
+        // Safety: per the contract of `ScopedKey::guard_stack`, this stack is
+        // dropped before `'a` ends, on this thread, and so are its guards.
+        let guard = unsafe { self.key.set_guard(t) };
+        self.guards.push(guard);
+    }
+
+    /// Removes the value pushed last, returning whether there was one.
+    pub fn pop(&mut self) -> bool {
+        self.guards.pop().is_some()
+    }
+
+    /// Returns how many values this stack has installed.
+    pub fn len(&self) -> usize {
+        self.guards.len()
+    }
+
+    /// Returns whether this stack has no values installed.
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+}
+
+impl<T: ?Sized> Drop for GuardStack<'_, T> {
+    fn drop(&mut self) {
+        while self.pop() {}
+    }
+}
+
+/// A guard which owns a stack of values installed in a [`ScopedKey`], and
+/// keeps them installed until it is dropped.
+///
+/// Created by [`ScopedKey::install_stack`].
+pub struct StackInstallGuard<T: 'static> {
+    key: &'static ScopedKey<T>,
+    values: Vec<Heap<T>>,
+    frames: Vec<Heap<Frame<T>>>,
+    // The frames are linked into this thread's list of scopes.
+    _not_send: marker::PhantomData<*const ()>,
+}
+
+impl<T> StackInstallGuard<T> {
+    pub(crate) fn new(key: &'static ScopedKey<T>, values: Vec<T>) -> Self {
+        let mut guard = StackInstallGuard {
+            key,
+            values: Vec::with_capacity(values.len()),
+            frames: Vec::with_capacity(values.len()),
+            _not_send: marker::PhantomData,
+        };
+        for value in values {
+            key.check_set();
+            let value = Heap::new(value);
+            let frame = Heap::new(Frame::new(Some(value.ptr())));
+            // Safety: the frame and value are on the heap, so they never
+            // move, and are only freed after the frame is unlinked. If the
+            // guard is leaked, so are they.
+            unsafe { key.link(frame.get()) };
+            guard.values.push(value);
+            guard.frames.push(frame);
+            key.entered();
+        }
+        guard
+    }
+}
+
+impl<T> Drop for StackInstallGuard<T> {
+    fn drop(&mut self) {
+        for frame in self.frames.iter().rev() {
+            self.key.exit(frame.get());
+        }
+    }
+}
+
+/// A guard which hides the value of a [`ScopedKey`] until it is dropped.
+///
+/// Created by [`ScopedKey::suspend`].
+pub struct SuspendGuard<T: ?Sized + 'static> {
+    key: &'static ScopedKey<T>,
+    frame: Heap<Frame<T>>,
+    // The frame is linked into this thread's list of scopes.
+    _not_send: marker::PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> SuspendGuard<T> {
+    pub(crate) fn new(key: &'static ScopedKey<T>) -> Self {
+        let frame = Heap::new(Frame::new(None));
+        // Safety: the frame is on the heap, so it never moves, and the guard
+        // is neither `Send` nor dropped without unlinking it.
+        unsafe { key.link(frame.get()) };
+        SuspendGuard {
+            key,
+            frame,
+            _not_send: marker::PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for SuspendGuard<T> {
+    fn drop(&mut self) {
+        self.key.exit(self.frame.get());
+    }
+}
+
+/// Copy-on-write access to the value of a [`ScopedKey`].
+///
+/// Handed out by [`ScopedKey::with_cow`]. The current value is only cloned
+/// the first time it is modified; from then on the modified copy is installed
+/// in the key until `with_cow` returns.
+pub struct CowGuard<'a, T: Clone + 'static> {
+    key: &'static ScopedKey<T>,
+    borrowed: NonNull<T>,
+    /// The innermost frame when the guard was created, which the copy is
+    /// installed above.
+    base: *const Frame<T>,
+    owned: Option<(Heap<T>, Heap<Frame<T>>)>,
+    // `'a` is invariant so the guards of nested calls can't be swapped, and
+    // the guard may link a frame into this thread's list of scopes.
+    _marker: marker::PhantomData<*mut &'a ()>,
+}
+
+impl<'a, T: Clone> CowGuard<'a, T> {
+    pub(crate) fn new(key: &'static ScopedKey<T>, borrowed: &'a T) -> Self {
+        CowGuard {
+            key,
+            borrowed: NonNull::from(borrowed),
+            base: key.top(),
+            owned: None,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Returns the value, which is the modified copy if there is one.
+    pub fn get(&self) -> &T {
+        match &self.owned {
+            Some((value, _)) => value.get(),
+            // Safety: `with_cow` keeps the borrowed value alive
+            None => unsafe { self.borrowed.as_ref() },
+        }
+    }
+
+    /// Returns whether the value has been cloned by `modify`.
+    pub fn is_owned(&self) -> bool {
+        self.owned.is_some()
+    }
+
+    /// Modifies the value, cloning it first if this is the first
+    /// modification.
+    ///
+    /// The modified copy is visible to `with` once `f` returns. While `f` is
+    /// running, `with` observes the value the key had before `with_cow` was
+    /// called.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if a scope of the key entered inside
+    /// `with_cow` is still active, as the copy would otherwise end up
+    /// shadowing it.
+    pub fn modify<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        struct Relink<'b, T: 'static> {
+            key: &'static ScopedKey<T>,
+            frame: &'b Frame<T>,
+            first: bool,
+        }
+        impl<T> Drop for Relink<'_, T> {
+            fn drop(&mut self) {
+                // Safety: the frame is on the heap, so it never moves, and
+                // the guard is neither `Send` nor dropped without unlinking it.
+                unsafe { self.key.link(self.frame) };
+                if self.first {
+                    self.key.entered();
+                }
+            }
+        }
+        let expected = match &self.owned {
+            Some((_, frame)) => frame.get() as *const Frame<T>,
+            None => self.base,
+        };
+        if !ptr::eq(self.key.top(), expected) {
+            panic!(
+                "cannot modify scoped thread local variable `{}` while a scope nested inside \
+                 `with_cow` is active",
+                self.key.display_name()
+            );
+        }
+        if let Some((_, frame)) = &self.owned {
+            // Take the copy out of the key so `f` has exclusive access.
+            self.key.unlink(frame.get());
+        }
+        let first = self.owned.is_none();
+        if first {
+            let value = Heap::new(self.get().clone());
+            let frame = Heap::new(Frame::new(Some(value.ptr())));
+            self.owned = Some((value, frame));
+        }
+        let (value, frame) = self.owned.as_ref().unwrap();
+        let _relink = Relink {
+            key: self.key,
+            frame: frame.get(),
+            first,
+        };
+        // Safety: the copy isn't linked into the key while `f` runs
+        unsafe { f(&mut *value.ptr().as_ptr()) }
+    }
+}
+
+impl<T: Clone> Drop for CowGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some((_, frame)) = &self.owned {
+            self.key.exit(frame.get());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    scoped_thread_local!(static FOO: u32);
+
+    #[test]
+    fn scope_guard() {
+        let (one, two) = (1, 2);
+        let outer = unsafe { FOO.set_guard(&one) };
+        assert_eq!(FOO.get(), 1);
+        let inner = unsafe { FOO.set_guard(&two) };
+        assert_eq!(FOO.get(), 2);
+        drop(inner);
+        assert_eq!(FOO.get(), 1);
+        drop(outer);
+        assert!(!FOO.is_set());
+    }
+
+    #[test]
+    fn cow_guard() {
+        use std::cell::Cell;
+
+        thread_local!(static CLONES: Cell<u32> = const { Cell::new(0) });
+
+        #[derive(Debug, PartialEq)]
+        struct Counted(u32);
+        impl Clone for Counted {
+            fn clone(&self) -> Counted {
+                CLONES.with(|c| c.set(c.get() + 1));
+                Counted(self.0)
+            }
+        }
+        scoped_thread_local!(static BAR: Counted);
+
+        let clones = || CLONES.with(|c| c.get());
+        BAR.set(&Counted(1), || {
+            BAR.with_cow(|cow| {
+                assert_eq!(cow.get().0, 1);
+                assert!(!cow.is_owned());
+                assert_eq!(clones(), 0);
+
+                cow.modify(|v| {
+                    // The copy isn't installed until `modify` returns.
+                    assert_eq!(BAR.with(|v| v.0), 1);
+                    v.0 = 2;
+                });
+                assert!(cow.is_owned());
+                assert_eq!(BAR.with(|v| v.0), 2);
+
+                cow.modify(|v| v.0 += 1);
+                assert_eq!(clones(), 1);
+                assert_eq!(cow.get().0, 3);
+                assert_eq!(BAR.with(|v| v.0), 3);
+                BAR.set(&Counted(4), || assert_eq!(BAR.with(|v| v.0), 4));
+                assert_eq!(BAR.with(|v| v.0), 3);
+            });
+            assert_eq!(BAR.with(|v| v.0), 1);
+        });
+        assert!(!BAR.is_set());
+    }
+
+    #[test]
+    fn cow_guard_nested_set() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        scoped_thread_local!(static BAR: Vec<u32>);
+
+        BAR.set(&vec![1], || {
+            BAR.with_cow(|cow| {
+                BAR.set(&vec![2], || {
+                    let first = panic::catch_unwind(AssertUnwindSafe(|| cow.modify(|v| v.push(3))));
+                    assert!(first.is_err());
+                    assert_eq!(BAR.with(|v| v.clone()), [2]);
+                });
+                cow.modify(|v| v.push(3));
+                BAR.set(&vec![2], || {
+                    let again = panic::catch_unwind(AssertUnwindSafe(|| cow.modify(|v| v.push(4))));
+                    assert!(again.is_err());
+                    assert_eq!(BAR.with(|v| v.clone()), [2]);
+                });
+                assert_eq!(BAR.with(|v| v.clone()), [1, 3]);
+            });
+        });
+    }
+
+    #[test]
+    fn set_opt_guard() {
+        let one = 1;
+        let none = unsafe { FOO.set_opt_guard(None) };
+        assert!(none.is_none());
+        assert!(!FOO.is_set());
+
+        let guard = unsafe { FOO.set_opt_guard(Some(&one)) };
+        assert!(guard.is_some());
+        assert_eq!(FOO.get(), 1);
+        FOO.set(&2, || {
+            let _none = unsafe { FOO.set_opt_guard(None) };
+            assert_eq!(FOO.get(), 2);
+        });
+        drop(guard);
+        assert!(!FOO.is_set());
+    }
+
+    #[test]
+    fn combine_guards() {
+        scoped_thread_local!(static NAME: str);
+
+        let (name, one, two) = (String::from("x"), 1, 2);
+        FOO.set(&one, || {
+            let guard =
+                unsafe { crate::combine_guards(NAME.set_guard(&name[..]), FOO.set_guard(&two)) };
+            assert_eq!(NAME.with(|n| n.to_string()), "x");
+            assert_eq!(FOO.get(), 2);
+            drop(guard);
+            assert!(!NAME.is_set());
+            assert_eq!(FOO.get(), 1);
+        });
+    }
+
+    #[test]
+    fn guard_group() {
+        use std::cell::RefCell;
+
+        struct Logged<'a>(&'a RefCell<Vec<u32>>, u32);
+        impl Drop for Logged<'_> {
+            fn drop(&mut self) {
+                self.0.borrow_mut().push(self.1);
+            }
+        }
+
+        let log = RefCell::new(Vec::new());
+        let mut group = crate::GuardGroup::new();
+        (1..4).for_each(|i| group.push(Logged(&log, i)));
+        drop(group);
+        assert_eq!(*log.borrow(), [3, 2, 1]);
+
+        log.borrow_mut().clear();
+        let mut group = crate::GuardGroup::new();
+        (1..4).for_each(|i| group.push(Logged(&log, i)));
+        assert_eq!(group.len(), 3);
+        group.drop_in_order();
+        assert_eq!(*log.borrow(), [1, 2, 3]);
+
+        let (one, two, three) = (1, 2, 3);
+        let outer = unsafe { FOO.set_guard(&one) };
+        let mut group = crate::GuardGroup::new();
+        group.push(unsafe { FOO.set_guard(&two) });
+        group.push(unsafe { FOO.set_guard(&three) });
+        assert_eq!(FOO.get(), 3);
+        group.drop_in_order();
+        assert_eq!(FOO.get(), 1);
+        drop(outer);
+        assert!(!FOO.is_set());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn leaked_guards() {
+        static ONE: u32 = 1;
+
+        assert_eq!(crate::leaked_guards(), 0);
+        let guard = unsafe { FOO.set_guard(&ONE) };
+        assert_eq!(crate::leaked_guards(), 1);
+        drop(guard);
+        assert_eq!(crate::leaked_guards(), 0);
+
+        // Leaking a guard of a `'static` value is fine, but still counted.
+        std::mem::forget(unsafe { FOO.set_guard(&ONE) });
+        assert_eq!(crate::leaked_guards(), 1);
+    }
+
+    #[test]
+    fn guard_stack() {
+        let values = [1, 2, 3];
+        FOO.set(&0, || {
+            let mut stack = unsafe { FOO.guard_stack() };
+            for v in &values {
+                stack.push(v);
+            }
+            assert_eq!((stack.len(), FOO.get()), (3, 3));
+            assert!(stack.pop());
+            assert_eq!((stack.len(), FOO.get()), (2, 2));
+            drop(stack);
+            assert_eq!(FOO.get(), 0);
+        });
+
+        let mut stack = unsafe { FOO.guard_stack() };
+        assert!(!stack.pop());
+        assert!(stack.is_empty());
+    }
+}
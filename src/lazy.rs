@@ -0,0 +1,30 @@
+//! Lazily initialized process-wide state.
+
+use std::{cell::UnsafeCell, sync::Once};
+
+/// A value initialized on first access, usable in a `static`.
+///
+/// The initializer is passed to `get` rather than stored, as function
+/// pointers in `const fn` need a newer compiler than the crate supports.
+pub(crate) struct Lazy<T> {
+    once: Once,
+    value: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Lazy<T> {}
+
+impl<T> Lazy<T> {
+    pub(crate) const fn new() -> Self {
+        Lazy {
+            once: Once::new(),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    pub(crate) fn get(&self, init: impl FnOnce() -> T) -> &T {
+        // Safety: the value is only written once, before any reads
+        self.once
+            .call_once(|| unsafe { *self.value.get() = Some(init()) });
+        unsafe { (*self.value.get()).as_ref().unwrap() }
+    }
+}
@@ -45,11 +45,115 @@
 
 #![deny(missing_docs, warnings)]
 
-use std::{cell::Cell, marker, thread::LocalKey};
+use std::{
+    any::Any,
+    borrow::Borrow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt,
+    hash::{Hash, Hasher},
+    iter, marker, mem,
+    ops::ControlFlow,
+    panic::{self, AssertUnwindSafe},
+    ptr::{self, NonNull},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+    thread::{self, LocalKey},
+};
+
+use crate::lazy::Lazy;
+
+#[cfg(feature = "capture-locations")]
+use std::panic::Location;
+#[cfg(feature = "timing")]
+use std::time::{Duration, Instant};
 
 /// The macro. See the module level documentation for the description and examples.
+///
+/// Declaring the key as `static mut` creates a [`ScopedKeyMut`] instead, which
+/// hands out `&mut` access to the value for the duration of a closure.
+/// Declaring it as `static copy` creates a [`CopyScopedKey`], which stores a
+/// small `Copy` value itself rather than a reference to it.
+///
+/// A key may also be given a default with `= const EXPR`, which is placed in
+/// a `static` and observed whenever no value has been `set`:
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static FOO: u32 = const 42);
+///
+/// # fn main() {
+/// assert_eq!(FOO.with(|v| *v), 42);
+/// FOO.set(&1, || assert_eq!(FOO.with(|v| *v), 1));
+/// # }
+/// ```
+///
+/// For types implementing `FromStr`, `= env "VAR" or const EXPR` lets the
+/// environment variable `VAR` override the default. Each thread reads the
+/// variable the first time it needs the default, and falls back to `EXPR` if
+/// the variable isn't set or doesn't parse:
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static LEVEL: u8 = env "MY_APP_LOG_LEVEL" or const 3);
+///
+/// # fn main() {
+/// assert_eq!(LEVEL.with(|v| *v), 3);
+/// # }
+/// ```
 #[macro_export]
 macro_rules! scoped_thread_local {
+    ($(#[$attrs:meta])* $vis:vis static copy $name:ident: $ty:ty) => (
+        $(#[$attrs])*
+        $vis static $name: $crate::CopyScopedKey<$ty> = unsafe {
+            ::std::thread_local!(static FOO: ::std::cell::Cell<usize> = const {
+                ::std::cell::Cell::new(0)
+            });
+            // Safety: nothing else can access FOO since it's hidden in its own scope
+            $crate::CopyScopedKey::new(&FOO)
+        };
+    );
+    ($(#[$attrs:meta])* $vis:vis static mut $name:ident: $ty:ty) => (
+        $(#[$attrs])*
+        $vis static $name: $crate::ScopedKeyMut<$ty> = unsafe {
+            ::std::thread_local!(static FOO: ::std::cell::Cell<*const ()> = const {
+                ::std::cell::Cell::new(::std::ptr::null())
+            });
+            // Safety: nothing else can access FOO since it's hidden in its own scope
+            $crate::ScopedKeyMut::new(&FOO)
+        };
+    );
+    ($(#[$attrs:meta])* $vis:vis static $name:ident: $ty:ty = const $init:expr) => (
+        $(#[$attrs])*
+        $vis static $name: $crate::ScopedKey<$ty> = unsafe {
+            ::std::thread_local!(static FOO: ::std::cell::Cell<*const ()> = const {
+                ::std::cell::Cell::new(::std::ptr::null())
+            });
+            static DEFAULT: $ty = $init;
+            // Safety: nothing else can access FOO since it's hidden in its own scope
+            $crate::ScopedKey::new_with_default(&FOO, ::std::stringify!($name), &DEFAULT)
+        };
+    );
+    ($(#[$attrs:meta])* $vis:vis static $name:ident: $ty:ty = env $var:literal or const $init:expr) => (
+        $(#[$attrs])*
+        $vis static $name: $crate::ScopedKey<$ty> = unsafe {
+            ::std::thread_local!(static FOO: ::std::cell::Cell<*const ()> = const {
+                ::std::cell::Cell::new(::std::ptr::null())
+            });
+            ::std::thread_local!(static ENV: $crate::EnvDefault = $crate::EnvDefault::from_env::<$ty>($var));
+            static DEFAULT: $ty = $init;
+            // Safety: nothing else can access FOO since it's hidden in its own scope
+            $crate::ScopedKey::new_with_env_default(&FOO, ::std::stringify!($name), &DEFAULT, &ENV)
+        };
+    );
     ($(#[$attrs:meta])* $vis:vis static $name:ident: $ty:ty) => (
         $(#[$attrs])*
         $vis static $name: $crate::ScopedKey<$ty> = unsafe {
@@ -57,11 +161,175 @@ macro_rules! scoped_thread_local {
                 ::std::cell::Cell::new(::std::ptr::null())
             });
             // Safety: nothing else can access FOO since it's hidden in its own scope
-            $crate::ScopedKey::new(&FOO)
+            $crate::ScopedKey::new(&FOO, ::std::stringify!($name))
         };
     )
 }
 
+/// Declares a scoped key together with a module of free functions accessing
+/// it.
+///
+/// `scoped_context! { pub FOO: Config => foo; }` declares the key `FOO` just
+/// like `scoped_thread_local!(pub static FOO: Config)` does, plus a module
+/// `foo` with the functions `set`, `with`, `try_with`, `is_set`, `get`,
+/// `try_get` and `cloned`. `get` and `try_get` can only be called for `Copy`
+/// types and `cloned` for `Clone` types. The module name has to be given
+/// explicitly, as `macro_rules!` can't derive it from the key's name. Only
+/// sized types are supported, and the macro must be used at module level
+/// rather than inside a function.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// pub struct Config {
+///     verbose: bool,
+/// }
+///
+/// scoped_context! {
+///     /// The configuration of the current command.
+///     pub CONFIG: Config => config;
+/// }
+///
+/// # fn main() {
+/// config::set(&Config { verbose: true }, || {
+///     assert!(config::with(|c| c.verbose));
+///     assert_eq!(config::cloned(), Config { verbose: true });
+/// });
+/// assert!(config::try_with(|_| ()).is_err());
+/// # }
+/// ```
+#[macro_export]
+macro_rules! scoped_context {
+    ($(#[$attrs:meta])* $name:ident: $ty:ty => $module:ident;) => (
+        $crate::scoped_thread_local!($(#[$attrs])* static $name: $ty);
+        $crate::scoped_context!(@accessors pub(super), $name, $ty, $module);
+    );
+    ($(#[$attrs:meta])* $vis:vis $name:ident: $ty:ty => $module:ident;) => (
+        $crate::scoped_thread_local!($(#[$attrs])* $vis static $name: $ty);
+        $crate::scoped_context!(@accessors $vis, $name, $ty, $module);
+    );
+    (@accessors $vis:vis, $name:ident, $ty:ty, $module:ident) => (
+        #[doc = ::std::concat!("Accessors for [`", ::std::stringify!($name), "`].")]
+        #[allow(dead_code)]
+        $vis mod $module {
+            #[allow(unused_imports)]
+            use super::*;
+
+            /// See [`ScopedKey::set`]($crate::ScopedKey::set).
+            $vis fn set<F, R>(t: &$ty, f: F) -> R
+            where
+                F: FnOnce() -> R,
+            {
+                super::$name.set(t, f)
+            }
+
+            /// See [`ScopedKey::with`]($crate::ScopedKey::with).
+            $vis fn with<F, R>(f: F) -> R
+            where
+                F: FnOnce(&$ty) -> R,
+            {
+                super::$name.with(f)
+            }
+
+            /// See [`ScopedKey::try_with`]($crate::ScopedKey::try_with).
+            $vis fn try_with<F, R>(f: F) -> ::std::result::Result<R, $crate::AccessError>
+            where
+                F: FnOnce(&$ty) -> R,
+            {
+                super::$name.try_with(f)
+            }
+
+            /// See [`ScopedKey::is_set`]($crate::ScopedKey::is_set).
+            $vis fn is_set() -> bool {
+                super::$name.is_set()
+            }
+
+            // The `for<'a>` keeps these bounds from being rejected as
+            // trivially false for types which aren't `Copy` or `Clone`.
+
+            /// Returns a copy of the current value. See
+            /// [`ScopedKey::get`]($crate::ScopedKey::get).
+            $vis fn get() -> $ty
+            where
+                for<'a> $ty: ::std::marker::Copy,
+            {
+                super::$name.with(|v| *v)
+            }
+
+            /// Returns a copy of the current value, or `None` if there is
+            /// none.
+            $vis fn try_get() -> ::std::option::Option<$ty>
+            where
+                for<'a> $ty: ::std::marker::Copy,
+            {
+                super::$name.try_with(|v| *v).ok()
+            }
+
+            /// Returns a clone of the current value.
+            ///
+            /// # Panics
+            ///
+            /// This function will panic under the same conditions as
+            /// [`ScopedKey::with`]($crate::ScopedKey::with).
+            $vis fn cloned() -> $ty
+            where
+                for<'a> $ty: ::std::clone::Clone,
+            {
+                super::$name.with(|v| ::std::clone::Clone::clone(v))
+            }
+        }
+    );
+}
+
+mod arena;
+mod context;
+#[cfg(feature = "debug-crossthread")]
+mod crossthread;
+mod guard;
+mod lazy;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mutable;
+mod packed;
+mod pool;
+#[cfg(feature = "registry")]
+mod registry;
+#[cfg(feature = "sampling")]
+mod sampling;
+mod stream;
+#[cfg(any(test, feature = "test-util"))]
+mod test_util;
+mod traits;
+mod types;
+
+pub use crate::arena::ScopedAlloc;
+pub use crate::context::{ContextGuard, ContextKey, ContextMap};
+#[cfg(debug_assertions)]
+pub use crate::guard::leaked_guards;
+pub use crate::guard::{
+    combine_guards, CombinedGuard, CowGuard, GuardGroup, GuardStack, ScopeGuard, StackInstallGuard,
+    SuspendGuard,
+};
+#[cfg(feature = "metrics")]
+pub use crate::metrics::MetricRecorder;
+pub use crate::mutable::ScopedKeyMut;
+pub use crate::packed::{CopyScopedKey, Packed};
+pub use crate::pool::Executor;
+#[cfg(feature = "registry")]
+pub use crate::registry::{
+    dump_active, export_scope_tree, on_scope_enter, on_scope_exit, set_max_active_keys,
+};
+#[cfg(feature = "sampling")]
+pub use crate::sampling::set_sample_rate;
+pub use crate::stream::{ScopedStream, Stream};
+#[cfg(any(test, feature = "test-util"))]
+pub use crate::test_util::SavedState;
+pub use crate::traits::ScopedContext;
+
 /// Type representing a thread local storage key corresponding to a reference
 /// to the type parameter `T`.
 ///
@@ -69,24 +337,92 @@ macro_rules! scoped_thread_local {
 /// type `T` scoped to a particular lifetime. Keys provides two methods, `set`
 /// and `with`, both of which currently use closures to control the scope of
 /// their contents.
-pub struct ScopedKey<T> {
+///
+/// The type `T` may be unsized, such as `str` or `[U]`.
+pub struct ScopedKey<T: ?Sized> {
     inner: &'static LocalKey<Cell<*const ()>>,
+    name: &'static str,
+    default: Option<NonNull<T>>,
+    env_default: Option<&'static LocalKey<EnvDefault>>,
+    #[cfg(feature = "metrics")]
+    counters: metrics::Counters,
+    #[cfg(feature = "sampling")]
+    samples: sampling::Samples,
     _marker: marker::PhantomData<T>,
 }
 
-unsafe impl<T> Sync for ScopedKey<T> {}
+unsafe impl<T: ?Sized> Sync for ScopedKey<T> {}
+
+#[doc(hidden)]
+/// The per-thread default of a key declared with `= env "VAR" or const EXPR`,
+/// or `None` if `VAR` was unset or didn't parse.
+pub struct EnvDefault(Option<Box<dyn Any>>);
+
+impl EnvDefault {
+    #[doc(hidden)]
+    pub fn from_env<T: std::str::FromStr + 'static>(var: &str) -> Self {
+        let value = env::var(var).ok().and_then(|v| v.parse::<T>().ok());
+        EnvDefault(value.map(|v| Box::new(v) as Box<dyn Any>))
+    }
+}
+
+impl<T: ?Sized> ScopedKey<T> {
+    #[doc(hidden)]
+    /// # Safety
+    /// `inner` must only be accessed through `ScopedKey`'s API
+    pub const unsafe fn new(inner: &'static LocalKey<Cell<*const ()>>, name: &'static str) -> Self {
+        Self {
+            inner,
+            name,
+            default: None,
+            env_default: None,
+            #[cfg(feature = "metrics")]
+            counters: metrics::Counters::new(),
+            #[cfg(feature = "sampling")]
+            samples: sampling::Samples::new(),
+            _marker: marker::PhantomData,
+        }
+    }
 
-impl<T> ScopedKey<T> {
     #[doc(hidden)]
     /// # Safety
     /// `inner` must only be accessed through `ScopedKey`'s API
-    pub const unsafe fn new(inner: &'static LocalKey<Cell<*const ()>>) -> Self {
+    pub const unsafe fn new_with_default(
+        inner: &'static LocalKey<Cell<*const ()>>,
+        name: &'static str,
+        default: &'static T,
+    ) -> Self {
         Self {
             inner,
+            name,
+            default: Some(NonNull::new_unchecked(default as *const T as *mut T)),
+            env_default: None,
+            #[cfg(feature = "metrics")]
+            counters: metrics::Counters::new(),
+            #[cfg(feature = "sampling")]
+            samples: sampling::Samples::new(),
             _marker: marker::PhantomData,
         }
     }
 
+    #[doc(hidden)]
+    /// # Safety
+    /// `inner` must only be accessed through `ScopedKey`'s API
+    pub const unsafe fn new_with_env_default(
+        inner: &'static LocalKey<Cell<*const ()>>,
+        name: &'static str,
+        default: &'static T,
+        env_default: &'static LocalKey<EnvDefault>,
+    ) -> Self {
+        // `default_value` turns the erased address back into a `*const T`,
+        // which is only possible for thin pointers.
+        assert!(mem::size_of::<*const T>() == mem::size_of::<*const ()>());
+        Self {
+            env_default: Some(env_default),
+            ..Self::new_with_default(inner, name, default)
+        }
+    }
+
     /// Inserts a value into this scoped thread local storage slot for a
     /// duration of a closure.
     ///
@@ -96,6 +432,12 @@ impl<T> ScopedKey<T> {
     /// Upon return, this function will restore the previous value, if any
     /// was available.
     ///
+    /// # Panics
+    ///
+    /// In debug builds, this function panics on return if a scope with a
+    /// value entered inside `f`, e.g. through a leaked guard, is still
+    /// active, as the value `t` would then not have been the current one.
+    ///
     /// # Examples
     ///
     /// ```
@@ -120,28 +462,295 @@ impl<T> ScopedKey<T> {
     /// });
     /// # }
     /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
     pub fn set<F, R>(&'static self, t: &T, f: F) -> R
     where
         F: FnOnce() -> R,
     {
-        struct Reset {
-            key: &'static LocalKey<Cell<*const ()>>,
-            val: *const (),
+        self.enter(Frame::new_here(Some(NonNull::from(t))), f)
+    }
+
+    /// Inserts a value into this scoped thread local storage slot for a
+    /// duration of a closure, handing the closure a [`SharedRef`] which other
+    /// threads can install the same value with.
+    ///
+    /// Sharing is sound because `T` is `Sync`, and the `SharedRef` borrows
+    /// `t`, so it can't be used by threads outliving it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static CONFIG: str);
+    ///
+    /// # fn main() {
+    /// CONFIG.set_shared("release", |shared| {
+    ///     let child = std::thread::spawn(move || shared.install(|| CONFIG.with(|c| c.len())));
+    ///     assert_eq!(child.join().unwrap(), 7);
+    /// });
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn set_shared<'a, F, R>(&'static self, t: &'a T, f: F) -> R
+    where
+        T: Sync,
+        F: FnOnce(SharedRef<'a, T>) -> R,
+    {
+        let shared = SharedRef {
+            key: self,
+            value: t,
+        };
+        self.set(t, || f(shared))
+    }
+
+    /// Inserts a value into this scoped thread local storage slot until the
+    /// returned guard is dropped.
+    ///
+    /// This is the guard-based counterpart of `set`, for when the scope can't
+    /// be expressed as a closure. The guard keeps its state on the heap, so
+    /// this allocates.
+    ///
+    /// # Safety
+    ///
+    /// The guard must be dropped before the borrow of `t` ends, on the thread
+    /// that created it. Leaking it (e.g. with `mem::forget`) would leave a
+    /// dangling reference in the slot. In debug builds [`leaked_guards`] can
+    /// help track such leaks down.
+    ///
+    /// A guard created inside the closure of a `set` of the same key must
+    /// also be dropped before that closure returns, so that `set` restores
+    /// the value it shadowed. Debug builds panic when `set` returns while
+    /// such a guard is still alive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// let val = 1;
+    /// let guard = unsafe { FOO.set_guard(&val) };
+    /// assert_eq!(FOO.get(), 1);
+    /// drop(guard);
+    /// assert!(!FOO.is_set());
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub unsafe fn set_guard<'a>(&'static self, t: &'a T) -> ScopeGuard<'a, T> {
+        ScopeGuard::new(self, t)
+    }
+
+    /// Like `set_guard`, but only installs a value if there is one.
+    ///
+    /// Returns `None` without changing the key if `t` is `None`.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as for `set_guard` apply to the returned guard.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static USER: str);
+    ///
+    /// # fn main() {
+    /// fn handle(user: Option<&str>) -> bool {
+    ///     let _guard = unsafe { USER.set_opt_guard(user) };
+    ///     USER.is_set()
+    /// }
+    /// assert!(handle(Some("alice")));
+    /// assert!(!handle(None));
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub unsafe fn set_opt_guard<'a>(&'static self, t: Option<&'a T>) -> Option<ScopeGuard<'a, T>> {
+        match t {
+            Some(t) => Some(self.set_guard(t)),
+            None => None,
+        }
+    }
+
+    /// Installs the current value of `src` into `dst` for the duration of a
+    /// closure.
+    ///
+    /// Both keys refer to the same value while `f` runs, and `dst` is
+    /// restored to its previous value afterwards.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `src` has not been `set`, under the same
+    /// conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use scoped_tls::ScopedKey;
+    ///
+    /// scoped_thread_local!(static REQUEST: u32);
+    /// scoped_thread_local!(static PARENT: u32);
+    ///
+    /// # fn main() {
+    /// REQUEST.set(&7, || {
+    ///     ScopedKey::mirror(&REQUEST, &PARENT, || assert_eq!(PARENT.get(), 7));
+    /// });
+    /// assert!(!PARENT.is_set());
+    /// # }
+    /// ```
+    pub fn mirror<F, R>(src: &'static ScopedKey<T>, dst: &'static ScopedKey<T>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        src.with(|v| dst.set(v, f))
+    }
+
+    /// Returns an empty [`GuardStack`] for installing a varying number of
+    /// values in this key imperatively.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as for `set_guard` apply to the returned stack:
+    /// it must be dropped before the borrows of the values pushed onto it
+    /// end, on the thread that created it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static DIR: str);
+    ///
+    /// # fn main() {
+    /// let path = ["usr", "local", "bin"];
+    /// let mut stack = unsafe { DIR.guard_stack() };
+    /// for dir in &path {
+    ///     stack.push(dir);
+    /// }
+    /// DIR.with(|dir| assert_eq!(dir, "bin"));
+    /// stack.pop();
+    /// DIR.with(|dir| assert_eq!(dir, "local"));
+    /// drop(stack);
+    /// assert!(!DIR.is_set());
+    /// # }
+    /// ```
+    pub unsafe fn guard_stack<'a>(&'static self) -> GuardStack<'a, T> {
+        GuardStack::new(self)
+    }
+
+    /// Like `set`, but takes a raw pointer, e.g. one handed over through FFI.
+    ///
+    /// In debug builds the pointer is checked to be properly aligned for `T`
+    /// before it is installed, since a misaligned pointer would only cause
+    /// undefined behavior later on, in `with`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid to turn into a `&T` for the duration of `f`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `ptr` is null, or in debug builds if it is
+    /// not aligned for `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// let val = 3;
+    /// unsafe { FOO.set_raw(&val, || assert_eq!(FOO.get(), 3)) };
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub unsafe fn set_raw<F, R>(&'static self, ptr: *const T, f: F) -> R
+    where
+        T: Sized,
+        F: FnOnce() -> R,
+    {
+        let ptr = NonNull::new(ptr as *mut T).unwrap_or_else(|| {
+            panic!(
+                "cannot set scoped thread local variable `{}` to a null pointer",
+                self.display_name()
+            )
+        });
+        debug_assert!(
+            ptr.as_ptr() as usize % mem::align_of::<T>() == 0,
+            "cannot set scoped thread local variable `{}` to a pointer misaligned for its type",
+            self.display_name()
+        );
+        self.enter(Frame::new_here(Some(ptr)), f)
+    }
+
+    /// Like `set`, but also records how to format `t` so it shows up in
+    /// [`dump_active`].
+    #[cfg(feature = "registry")]
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn set_debug<F, R>(&'static self, t: &T, f: F) -> R
+    where
+        T: fmt::Debug,
+        F: FnOnce() -> R,
+    {
+        let mut frame = Frame::new_here(Some(NonNull::from(t)));
+        frame.debug = Some(<T as fmt::Debug>::fmt);
+        self.enter(frame, f)
+    }
+
+    /// Runs `f` with `frame` linked as the innermost scope.
+    fn enter<F, R>(&'static self, frame: Frame<T>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.check_set();
+        struct Reset<'a, T: ?Sized + 'static> {
+            key: &'static ScopedKey<T>,
+            frame: &'a Frame<T>,
         }
-        impl Drop for Reset {
+        impl<T: ?Sized> Drop for Reset<'_, T> {
             fn drop(&mut self) {
-                self.key.with(|c| c.set(self.val));
+                // The frame has to be unlinked whatever the outcome, so only
+                // report the check afterwards.
+                #[cfg(debug_assertions)]
+                let restored = thread::panicking() || !self.key.shadowed_by_value(self.frame);
+                self.key.exit(self.frame);
+                #[cfg(debug_assertions)]
+                debug_assert!(
+                    restored,
+                    "scoped thread local variable `{}` does not hold the value `set` installed \
+                     when `set` returns; a scope entered inside it is still active",
+                    self.key.display_name()
+                );
             }
         }
-        let prev = self.inner.with(|c| {
-            let prev = c.get();
-            c.set(t as *const T as *const ());
-            prev
-        });
+        // Safety: `_reset` unlinks the frame before it goes out of scope
+        unsafe { self.link(&frame) };
         let _reset = Reset {
-            key: self.inner,
-            val: prev,
+            key: self,
+            frame: &frame,
         };
+        self.entered();
+        #[cfg(feature = "sampling")]
+        {
+            if let Some(start) = sampling::sample() {
+                let r = f();
+                self.samples.record(start);
+                return r;
+            }
+        }
         f()
     }
 
@@ -150,9 +759,15 @@ impl<T> ScopedKey<T> {
     /// This function takes a closure which receives the value of this
     /// variable.
     ///
+    /// If `set` has not previously been called and the key was declared with a
+    /// default, the closure receives the default instead.
+    ///
     /// # Panics
     ///
-    /// This function will panic if `set` has not previously been called.
+    /// This function will panic if `set` has not previously been called and
+    /// the key has no default. If the `SCOPED_TLS_HINT` environment variable
+    /// is set, its value is appended to the panic message, so deployments can
+    /// point operators at further guidance.
     ///
     /// # Examples
     ///
@@ -173,104 +788,2964 @@ impl<T> ScopedKey<T> {
     where
         F: FnOnce(&T) -> R,
     {
-        let val = self.inner.with(|c| c.get());
-        assert!(
-            !val.is_null(),
-            "cannot access a scoped thread local variable without calling `set` first"
-        );
-        unsafe { f(&*(val as *const T)) }
+        #[cfg(feature = "metrics")]
+        self.counters.read();
+        match self.try_value() {
+            Ok(val) => unsafe { f(val.as_ref()) },
+            Err(AccessError::Unset) => panic!(
+                "cannot access scoped thread local variable `{}` without calling `set` first{}",
+                self.display_name(),
+                self.unset_hint()
+            ),
+            Err(AccessError::Destroyed) => panic!(
+                "cannot access scoped thread local variable `{}` during or after destruction",
+                self.display_name()
+            ),
+        }
     }
 
-    /// Test whether this TLS key has been `set` for the current thread.
-    pub fn is_set(&'static self) -> bool {
-        self.inner.with(|c| !c.get().is_null())
+    /// Like `with`, but without checking that a value is available in
+    /// release builds.
+    ///
+    /// The missing value is treated as unreachable, so the compiler drops the
+    /// branch to the panic, and the formatting code it pulls in, from the
+    /// caller. In debug builds this still checks, and panics just like
+    /// `with`.
+    ///
+    /// # Safety
+    ///
+    /// A value must be available, i.e. `try_with` would return `Ok`: either
+    /// `set` has been called, or the key has a default, and the thread local
+    /// storage of the key has not been destroyed yet.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     // Safety: we're inside `set`
+    ///     assert_eq!(unsafe { FOO.with_unchecked(|v| *v) }, 1);
+    /// });
+    /// # }
+    /// ```
+    pub unsafe fn with_unchecked<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        if cfg!(debug_assertions) {
+            self.with(f)
+        } else {
+            #[cfg(feature = "metrics")]
+            self.counters.read();
+            f(self.try_value().unwrap_unchecked().as_ref())
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        cell::Cell,
-        sync::mpsc::{channel, Sender},
-        thread,
-    };
+    /// Gets a value out of this scoped variable, if one is available.
+    ///
+    /// This is the non-panicking version of `with`: it returns an error
+    /// instead if `set` has not previously been called, or if the thread is
+    /// exiting and the storage backing this key has already been destroyed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use scoped_tls::AccessError;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// assert_eq!(FOO.try_with(|v| *v), Err(AccessError::Unset));
+    /// FOO.set(&1, || assert_eq!(FOO.try_with(|v| *v), Ok(1)));
+    /// # }
+    /// ```
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        #[cfg(feature = "metrics")]
+        self.counters.read();
+        self.try_value().map(|val| unsafe { f(val.as_ref()) })
+    }
+
+    /// Gets copy-on-write access to the value of this scoped variable.
+    ///
+    /// Reading through the [`CowGuard`] doesn't clone the value. The first
+    /// call to [`CowGuard::modify`] clones it and installs the copy in this
+    /// key for the rest of `f`, where nested calls to `with` observe it.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static NAMES: Vec<&'static str>);
+    ///
+    /// # fn main() {
+    /// NAMES.set(&vec!["a"], || {
+    ///     NAMES.with_cow(|names| {
+    ///         assert_eq!(names.get().len(), 1);
+    ///         names.modify(|names| names.push("b"));
+    ///         assert_eq!(NAMES.with(|names| names.len()), 2);
+    ///     });
+    ///     assert_eq!(NAMES.with(|names| names.len()), 1);
+    /// });
+    /// # }
+    /// ```
+    pub fn with_cow<F, R>(&'static self, f: F) -> R
+    where
+        T: Clone,
+        F: for<'a> FnOnce(&mut CowGuard<'a, T>) -> R,
+    {
+        self.with(|v| f(&mut CowGuard::new(self, v)))
+    }
+
+    /// Returns a clone of the value of this scoped variable with each of `ops`
+    /// applied to it in order.
+    ///
+    /// The scoped value itself is left untouched.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static LIMIT: u32);
+    ///
+    /// # fn main() {
+    /// LIMIT.set(&10, || {
+    ///     assert_eq!(LIMIT.transform(&[|n| n * 2, |n| n + 1]), 21);
+    ///     assert_eq!(LIMIT.get(), 10);
+    /// });
+    /// # }
+    /// ```
+    pub fn transform(&'static self, ops: &[fn(T) -> T]) -> T
+    where
+        T: Clone,
+    {
+        let value = self.with(T::clone);
+        ops.iter().fold(value, |value, op| op(value))
+    }
+
+    /// Gets a value out of this scoped variable, first setting it to the
+    /// result of `init` if no value is available.
+    ///
+    /// The initialized value lives on the stack of this call and is installed
+    /// only while `f` runs, where nested calls to `with` observe it. `init`
+    /// is not called if a value is already available.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static NAME: String);
+    ///
+    /// # fn main() {
+    /// let len = NAME.with_or_init(|| String::from("default"), |name| name.len());
+    /// assert_eq!(len, 7);
+    /// assert!(!NAME.is_set());
+    /// # }
+    /// ```
+    pub fn with_or_init<F, R, I>(&'static self, init: I, f: F) -> R
+    where
+        T: Sized,
+        I: FnOnce() -> T,
+        F: FnOnce(&T) -> R,
+    {
+        if self.is_set() {
+            self.with(f)
+        } else {
+            let value = init();
+            self.set(&value, || self.with(f))
+        }
+    }
+
+    /// Gets a value out of this scoped variable, along with whether it
+    /// shadows the value of an enclosing `set`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     assert_eq!(FOO.with_shadow_info(|v, shadows| (*v, shadows)), (1, false));
+    ///     FOO.set(&2, || {
+    ///         assert_eq!(FOO.with_shadow_info(|v, shadows| (*v, shadows)), (2, true));
+    ///     });
+    /// });
+    /// # }
+    /// ```
+    pub fn with_shadow_info<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T, bool) -> R,
+    {
+        let top = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        let shadows = unsafe {
+            top.as_ref()
+                .filter(|frame| frame.value.is_some())
+                .and_then(|frame| frame.prev.get().as_ref())
+                .map_or(false, |prev| prev.value.is_some())
+        };
+        self.with(|v| f(v, shadows))
+    }
+
+    /// Gets a value out of this scoped variable, installing `default` for
+    /// the duration of `f` if no value is available.
+    ///
+    /// Unlike reading with a fallback, the installed default is visible to
+    /// nested calls to `with` made by `f`. Once `f` returns the key is unset
+    /// again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// fn nested() -> u32 {
+    ///     FOO.with(|v| *v)
+    /// }
+    ///
+    /// assert_eq!(FOO.with_or_install(&1, |_| nested()), 1);
+    /// assert!(!FOO.is_set());
+    /// FOO.set(&2, || assert_eq!(FOO.with_or_install(&1, |_| nested()), 2));
+    /// # }
+    /// ```
+    pub fn with_or_install<F, R>(&'static self, default: &T, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        if self.is_set() {
+            self.with(f)
+        } else {
+            self.set(default, || self.with(f))
+        }
+    }
+
+    /// Gets a value out of this scoped variable, falling back to a single
+    /// value shared by all threads if no value is available.
+    ///
+    /// The shared value is created by calling `init` the first time any
+    /// thread falls back to it, and then lives for the rest of the process.
+    /// `init` runs while a process-wide lock is held, so it must not call
+    /// `with_or_global_once` itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static LIMIT: u32);
+    ///
+    /// # fn main() {
+    /// fn init() -> u32 {
+    ///     100
+    /// }
+    ///
+    /// assert_eq!(LIMIT.with_or_global_once(init, |v| *v), 100);
+    /// LIMIT.set(&1, || assert_eq!(LIMIT.with_or_global_once(init, |v| *v), 1));
+    /// # }
+    /// ```
+    pub fn with_or_global_once<F, R>(&'static self, init: fn() -> T, f: F) -> R
+    where
+        T: Sized + Send + Sync + 'static,
+        F: FnOnce(&T) -> R,
+    {
+        type Globals = HashMap<usize, &'static (dyn Any + Send + Sync)>;
+        static GLOBALS: Lazy<Mutex<Globals>> = Lazy::new();
+
+        // `try_with` only fails without calling its closure, so `f` is
+        // still there for the global.
+        let mut f = Some(f);
+        if let Ok(r) = self.try_with(|v| f.take().unwrap()(v)) {
+            return r;
+        }
+        let global = *GLOBALS
+            .get(Default::default)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(self as *const Self as usize)
+            .or_insert_with(|| Box::leak(Box::new(init())));
+        f.take().unwrap()(global.downcast_ref().unwrap())
+    }
+
+    /// Like `with`, but also measures how long `f` took to run.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: Vec<u32>);
+    ///
+    /// # fn main() {
+    /// FOO.set(&vec![1, 2, 3], || {
+    ///     let (sum, elapsed) = FOO.with_timed(|v| v.iter().sum::<u32>());
+    ///     assert_eq!(sum, 6);
+    ///     println!("summing took {:?}", elapsed);
+    /// });
+    /// # }
+    /// ```
+    #[cfg(feature = "timing")]
+    pub fn with_timed<F, R>(&'static self, f: F) -> (R, Duration)
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.with(|v| {
+            let start = Instant::now();
+            let r = f(v);
+            (r, start.elapsed())
+        })
+    }
+
+    /// Like `with`, but first checks that no scope has been entered on this
+    /// thread since [`global_scope_version`] returned `expected`.
+    ///
+    /// This enforces that a region of code doesn't change the context it runs
+    /// in.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the version differs from `expected`, or
+    /// under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     let version = scoped_tls::global_scope_version();
+    ///     // ... code which must not enter any scopes ...
+    ///     assert_eq!(FOO.with_at_version(version, |v| *v), 1);
+    /// });
+    /// # }
+    /// ```
+    pub fn with_at_version<F, R>(&'static self, expected: u64, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let version = global_scope_version();
+        assert!(
+            version == expected,
+            "scope version {} differs from the expected {} when accessing scoped thread local \
+             variable `{}`",
+            version,
+            expected,
+            self.display_name()
+        );
+        self.with(f)
+    }
+
+    /// Gets the value of the first of this key and `fallbacks` which is set.
+    ///
+    /// The keys are checked in order, starting with this one. This suits
+    /// layered configuration, e.g. a per-request key falling back to a
+    /// per-session and a global one.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if none of the keys is set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static REQUEST: u32);
+    /// scoped_thread_local!(static SESSION: u32);
+    /// scoped_thread_local!(static GLOBAL: u32 = const 10);
+    ///
+    /// # fn main() {
+    /// let timeout = || REQUEST.with_chain(&[&SESSION, &GLOBAL], |t| *t);
+    /// assert_eq!(timeout(), 10);
+    /// SESSION.set(&5, || {
+    ///     assert_eq!(timeout(), 5);
+    ///     REQUEST.set(&1, || assert_eq!(timeout(), 1));
+    /// });
+    /// # }
+    /// ```
+    pub fn with_chain<F, R>(&'static self, fallbacks: &[&'static ScopedKey<T>], f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        match self.first_set(fallbacks) {
+            Some(key) => key.with(f),
+            None => panic!(
+                "cannot access scoped thread local variable `{}` or any of its fallbacks \
+                 without calling `set` first",
+                self.display_name()
+            ),
+        }
+    }
+
+    /// Like `with_chain`, but returns `None` instead of panicking if none of
+    /// the keys is set.
+    pub fn try_with_chain<F, R>(
+        &'static self,
+        fallbacks: &[&'static ScopedKey<T>],
+        f: F,
+    ) -> Option<R>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.first_set(fallbacks).map(|key| key.with(f))
+    }
+
+    /// Returns the first of this key and `fallbacks` which is set.
+    fn first_set(
+        &'static self,
+        fallbacks: &[&'static ScopedKey<T>],
+    ) -> Option<&'static ScopedKey<T>> {
+        iter::once(self)
+            .chain(fallbacks.iter().cloned())
+            .find(|key| key.is_set())
+    }
+
+    /// Like `with`, but also passes `f` how many scopes of this key are
+    /// nested, counting the innermost one.
+    ///
+    /// Scopes are counted since the innermost `suspend`. A default value
+    /// observed outside of any scope has depth 0.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     FOO.set(&2, || assert_eq!(FOO.with_depth(|v, depth| (*v, depth)), (2, 2)));
+    /// });
+    /// # }
+    /// ```
+    pub fn with_depth<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T, usize) -> R,
+    {
+        self.with(|v| f(v, self.depth()))
+    }
+
+    /// Like `with_depth`, but returns `None` instead of panicking if there
+    /// is no value.
+    pub fn try_with_depth<F, R>(&'static self, f: F) -> Option<R>
+    where
+        F: FnOnce(&T, usize) -> R,
+    {
+        self.try_with(|v| f(v, self.depth())).ok()
+    }
+
+    /// Like `with`, but hands the value to `f` as a `&dyn Debug`.
+    ///
+    /// This lets the value be passed on to formatting sinks without making
+    /// them generic.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::fmt::Write;
+    ///
+    /// scoped_thread_local!(static USER: str);
+    ///
+    /// # fn main() {
+    /// let mut log = String::new();
+    /// USER.set("alice", || USER.with_as_debug(|user| write!(log, "user={:?}", user)))
+    ///     .unwrap();
+    /// assert_eq!(log, "user=\"alice\"");
+    /// # }
+    /// ```
+    pub fn with_as_debug<F, R>(&'static self, f: F) -> R
+    where
+        T: fmt::Debug,
+        F: FnOnce(&dyn fmt::Debug) -> R,
+    {
+        // `&T` is `Debug` and sized even if `T` itself isn't.
+        self.with(|v| f(&v))
+    }
+
+    /// Gets the value of this key as a `&dyn Any`, or `None` if there is
+    /// none.
+    ///
+    /// This lets generic code handling many kinds of keys downcast the value
+    /// where it knows the type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     assert_eq!(FOO.with_as_any(|v| v.downcast_ref::<u32>().copied()), Some(Some(1)));
+    /// });
+    /// assert_eq!(FOO.with_as_any(|_| ()), None);
+    /// # }
+    /// ```
+    pub fn with_as_any<F, R>(&'static self, f: F) -> Option<R>
+    where
+        T: Any + Sized,
+        F: FnOnce(&dyn Any) -> R,
+    {
+        self.try_with(|v| f(v)).ok()
+    }
+
+    /// Feeds the current value to `hasher` and passes the finished hash to
+    /// `f`.
+    ///
+    /// Any state already in `hasher` is part of the hash, so a fresh hasher
+    /// should be passed to get a hash of the value alone.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::collections::hash_map::DefaultHasher;
+    ///
+    /// scoped_thread_local!(static FOO: str);
+    ///
+    /// # fn main() {
+    /// let a = FOO.set("a", || FOO.with_hash(&mut DefaultHasher::new(), |h| h));
+    /// let b = FOO.set("b", || FOO.with_hash(&mut DefaultHasher::new(), |h| h));
+    /// assert_ne!(a, b);
+    /// # }
+    /// ```
+    pub fn with_hash<H, F, R>(&'static self, hasher: &mut H, f: F) -> R
+    where
+        T: Hash,
+        H: Hasher,
+        F: FnOnce(u64) -> R,
+    {
+        self.with(|v| v.hash(hasher));
+        f(hasher.finish())
+    }
+
+    /// Test whether this TLS key has been `set` for the current thread.
+    ///
+    /// Keys declared with a default always have a value available, so this
+    /// returns `true` for them even outside of `set`.
+    pub fn is_set(&'static self) -> bool {
+        self.value().is_some()
+    }
+
+    /// Tests the current value of this key against `pred`.
+    ///
+    /// Returns `false` without calling `pred` if the key is not set, which
+    /// makes this handy for keys used as mode selectors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// enum Mode {
+    ///     Fast,
+    ///     Safe,
+    /// }
+    ///
+    /// scoped_thread_local!(static MODE: Mode);
+    ///
+    /// # fn main() {
+    /// MODE.set(&Mode::Fast, || {
+    ///     assert!(MODE.is(|m| matches!(m, Mode::Fast)));
+    ///     assert!(!MODE.is(|m| matches!(m, Mode::Safe)));
+    /// });
+    /// assert!(!MODE.is(|_| true));
+    /// # }
+    /// ```
+    pub fn is<F>(&'static self, pred: F) -> bool
+    where
+        F: FnOnce(&T) -> bool,
+    {
+        self.try_with(pred).unwrap_or(false)
+    }
+
+    /// Temporarily removes the current value of this key, if any.
+    ///
+    /// Until the returned guard is dropped this key behaves as if `set` had
+    /// never been called. Nested calls to `set` work as usual. Dropping the
+    /// guard makes the suspended value visible again.
+    ///
+    /// The guard keeps its state on the heap, so this allocates.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     let guard = FOO.suspend();
+    ///     assert!(!FOO.is_set());
+    ///     drop(guard);
+    ///     assert_eq!(FOO.with(|v| *v), 1);
+    /// });
+    /// # }
+    /// ```
+    pub fn suspend(&'static self) -> SuspendGuard<T> {
+        SuspendGuard::new(self)
+    }
+
+    /// Registers `action` to run when the innermost scope of this key exits.
+    ///
+    /// Deferred actions run in reverse order of registration, while the
+    /// scope's value is still installed, and also when the scope is exited by
+    /// a panic. An action which panics while the thread is already panicking
+    /// will abort the process.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     FOO.defer(|| println!("leaving scope {}", FOO.get()));
+    ///     // ...
+    /// });
+    /// # }
+    /// ```
+    pub fn defer(&'static self, action: impl FnOnce() + 'static) {
+        let top = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        match unsafe { top.as_ref() } {
+            Some(frame) if frame.value.is_some() => {
+                frame.mark_read();
+                frame.deferred.push(Box::new(action));
+            }
+            _ => panic!(
+                "cannot defer to the exit of scoped thread local variable `{}` without calling `set` first",
+                self.display_name()
+            ),
+        }
+    }
+
+    /// Forbids nested scopes of this key until the innermost scope exits.
+    ///
+    /// After this is called, any attempt to `set` the key again before the
+    /// current scope ends will panic, including from within `suspend`. This
+    /// suits state that only ever moves forward.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static STATE: u32);
+    ///
+    /// # fn main() {
+    /// STATE.set(&1, || {
+    ///     STATE.fuse();
+    ///     assert!(std::panic::catch_unwind(|| STATE.set(&2, || {})).is_err());
+    /// });
+    /// STATE.set(&2, || {});
+    /// # }
+    /// ```
+    pub fn fuse(&'static self) {
+        let top = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        match unsafe { top.as_ref() } {
+            Some(frame) if frame.value.is_some() => {
+                frame.mark_read();
+                frame.fused_here.set(true);
+                frame.fused.set(true);
+            }
+            _ => panic!(
+                "cannot fuse scoped thread local variable `{}` without calling `set` first",
+                self.display_name()
+            ),
+        }
+    }
+
+    /// Inserts a value for the duration of a closure, unless it is equal to
+    /// the current value.
+    ///
+    /// If the key is already set to a value equal to `t`, `f` runs in the
+    /// current scope instead of a new nested one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     FOO.set_if_changed(&1, || assert!(!FOO.with_shadow_info(|_, shadows| shadows)));
+    ///     FOO.set_if_changed(&2, || assert!(FOO.with_shadow_info(|_, shadows| shadows)));
+    /// });
+    /// # }
+    /// ```
+    pub fn set_if_changed<F, R>(&'static self, t: &T, f: F) -> R
+    where
+        T: PartialEq,
+        F: FnOnce() -> R,
+    {
+        match self.try_with(|current| current == t) {
+            Ok(true) => f(),
+            _ => self.set(t, f),
+        }
+    }
+
+    /// Inserts a value for the duration of a closure returning a
+    /// `ControlFlow`.
+    ///
+    /// This is `set` specialized to closures which may stop early, so the
+    /// flow can be passed straight on to the caller. The previous value is
+    /// restored on both `Continue` and `Break`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::ops::ControlFlow;
+    ///
+    /// scoped_thread_local!(static LIMIT: u32);
+    ///
+    /// # fn main() {
+    /// let flow = LIMIT.set_cf(&3, || {
+    ///     for i in 0.. {
+    ///         if i == LIMIT.get() {
+    ///             return ControlFlow::Break(i);
+    ///         }
+    ///     }
+    ///     ControlFlow::Continue(())
+    /// });
+    /// assert_eq!(flow, ControlFlow::Break(3));
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn set_cf<B, C>(
+        &'static self,
+        t: &T,
+        f: impl FnOnce() -> ControlFlow<B, C>,
+    ) -> ControlFlow<B, C> {
+        self.set(t, f)
+    }
+
+    /// Inserts a value for the duration of a closure, marking any panic in
+    /// the closure as having happened inside this scope.
+    ///
+    /// If `f` panics with a string message, the unwind is resumed with the
+    /// message prefixed by the name of this key, which helps to pin down
+    /// panics in tests. Other payloads are resumed unchanged. The panic hook
+    /// only sees the original panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static REQUEST: u32);
+    ///
+    /// # fn main() {
+    /// let err = std::panic::catch_unwind(|| {
+    ///     REQUEST.set_assert_no_panic(&1, || panic!("oops"))
+    /// })
+    /// .unwrap_err();
+    /// assert_eq!(
+    ///     err.downcast_ref::<String>().unwrap(),
+    ///     "panicked inside a scope of scoped thread local variable `REQUEST`: oops"
+    /// );
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn set_assert_no_panic<F, R>(&'static self, t: &T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let payload = match panic::catch_unwind(AssertUnwindSafe(|| self.set(t, f))) {
+            Ok(r) => return r,
+            Err(payload) => payload,
+        };
+        let msg = match payload.downcast_ref::<&str>() {
+            Some(msg) => Some(*msg),
+            None => payload.downcast_ref::<String>().map(|msg| &msg[..]),
+        };
+        match msg {
+            Some(msg) => panic::resume_unwind(Box::new(format!(
+                "panicked inside a scope of scoped thread local variable `{}`: {}",
+                self.display_name(),
+                msg
+            ))),
+            None => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Inserts a value for the duration of a closure, along with a counter
+    /// for [`next_local_id`](ScopedKey::next_local_id).
+    ///
+    /// Each scope gets a fresh counter starting at 0, independent of the
+    /// counters of enclosing scopes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static TREE: &'static str);
+    ///
+    /// # fn main() {
+    /// TREE.set_with_counter(&"ast", || {
+    ///     assert_eq!(TREE.next_local_id(), 0);
+    ///     assert_eq!(TREE.next_local_id(), 1);
+    ///     TREE.set_with_counter(&"macro", || assert_eq!(TREE.next_local_id(), 0));
+    ///     assert_eq!(TREE.next_local_id(), 2);
+    /// });
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn set_with_counter<F, R>(&'static self, t: &T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let mut frame = Frame::new_here(Some(NonNull::from(t)));
+        frame.counter = Some(Cell::new(0));
+        self.enter(frame, f)
+    }
+
+    /// Returns the next id from the counter of the innermost scope entered
+    /// with `set_with_counter`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if no such scope is active, or if all scopes
+    /// are hidden by `suspend`.
+    pub fn next_local_id(&'static self) -> u64 {
+        let mut cur = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        while let Some(frame) = unsafe { cur.as_ref() } {
+            if frame.value.is_none() {
+                break;
+            }
+            if let Some(counter) = &frame.counter {
+                frame.mark_read();
+                let id = counter.get();
+                counter.set(id + 1);
+                return id;
+            }
+            cur = frame.prev.get();
+        }
+        panic!(
+            "cannot generate an id for scoped thread local variable `{}` without calling \
+             `set_with_counter` first",
+            self.display_name()
+        )
+    }
+
+    /// Inserts a value for the duration of a transaction, which `f` can
+    /// [`commit`](ScopedKey::commit).
+    ///
+    /// Once the scope has exited, `on_commit` is called with `t` if `commit`
+    /// was called while `f` ran, and `on_rollback` otherwise, including when
+    /// `f` panics. Returns the result of `f` and whether it committed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::cell::RefCell;
+    ///
+    /// scoped_thread_local!(static BATCH: RefCell<Vec<u32>>);
+    ///
+    /// # fn main() {
+    /// let batch = RefCell::new(Vec::new());
+    /// let flushed = RefCell::new(Vec::new());
+    /// let (_, committed) = BATCH.set_txn(
+    ///     &batch,
+    ///     |b| flushed.borrow_mut().append(&mut b.borrow_mut()),
+    ///     |b| b.borrow_mut().clear(),
+    ///     || {
+    ///         BATCH.with(|b| b.borrow_mut().push(1));
+    ///         BATCH.commit();
+    ///     },
+    /// );
+    /// assert!(committed);
+    /// assert_eq!(*flushed.borrow(), [1]);
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn set_txn<C, B, F, R>(
+        &'static self,
+        t: &T,
+        on_commit: C,
+        on_rollback: B,
+        f: F,
+    ) -> (R, bool)
+    where
+        C: FnOnce(&T),
+        B: FnOnce(&T),
+        F: FnOnce() -> R,
+    {
+        struct Rollback<'a, T: ?Sized, B: FnOnce(&T)>(Option<B>, &'a T);
+        impl<T: ?Sized, B: FnOnce(&T)> Drop for Rollback<'_, T, B> {
+            fn drop(&mut self) {
+                if let Some(on_rollback) = self.0.take() {
+                    on_rollback(self.1);
+                }
+            }
+        }
+        let committed = Cell::new(false);
+        let mut frame = Frame::new_here(Some(NonNull::from(t)));
+        frame.txn = Some(NonNull::from(&committed));
+        let mut rollback = Rollback(Some(on_rollback), t);
+        let r = self.enter(frame, f);
+        if committed.get() {
+            rollback.0 = None;
+            on_commit(t);
+        }
+        (r, committed.get())
+    }
+
+    /// Commits the transaction of the innermost scope entered with
+    /// `set_txn`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if no such scope is active, or if all scopes
+    /// are hidden by `suspend`.
+    pub fn commit(&'static self) {
+        let mut cur = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        while let Some(frame) = unsafe { cur.as_ref() } {
+            if frame.value.is_none() {
+                break;
+            }
+            if let Some(committed) = frame.txn {
+                frame.mark_read();
+                // Safety: `set_txn` keeps the flag alive for as long as the
+                // frame
+                return unsafe { committed.as_ref() }.set(true);
+            }
+            cur = frame.prev.get();
+        }
+        panic!(
+            "cannot commit scoped thread local variable `{}` without calling `set_txn` first",
+            self.display_name()
+        )
+    }
+
+    /// Inserts a value for the duration of a closure, then checks that
+    /// `invariant` still holds for it.
+    ///
+    /// This catches code inside the scope breaking the value through interior
+    /// mutability. The check is skipped if `f` panics.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `invariant(t)` is false once `f` returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::cell::Cell;
+    ///
+    /// scoped_thread_local!(static BUDGET: Cell<i32>);
+    ///
+    /// # fn main() {
+    /// let budget = Cell::new(10);
+    /// BUDGET.set_checked(&budget, |b| b.get() >= 0, || {
+    ///     BUDGET.with(|b| b.set(b.get() - 3));
+    /// });
+    /// assert_eq!(budget.get(), 7);
+    /// # }
+    /// ```
+    pub fn set_checked<F, R>(&'static self, t: &T, invariant: impl Fn(&T) -> bool, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let r = self.set(t, f);
+        assert!(
+            invariant(t),
+            "the invariant of scoped thread local variable `{}` was violated within its scope",
+            self.display_name()
+        );
+        r
+    }
+
+    /// Inserts a value for the duration of a closure, under a different name.
+    ///
+    /// Within `f`, panic messages and scope hooks refer to the key as
+    /// `display_name` instead of the name it was declared with. This lets a
+    /// library sharing one key between several uses tell them apart.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static CTX: u32);
+    ///
+    /// # fn main() {
+    /// CTX.set_named("request context", &1, || {
+    ///     let _suspended = CTX.suspend();
+    ///     let err = std::panic::catch_unwind(|| CTX.with(|_| ())).unwrap_err();
+    ///     assert!(err.downcast_ref::<String>().unwrap().contains("`request context`"));
+    /// });
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn set_named<F, R>(&'static self, display_name: &'static str, t: &T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let mut frame = Frame::new_here(Some(NonNull::from(t)));
+        frame.name = Some(display_name);
+        self.enter(frame, f)
+    }
+
+    /// Inserts a value for the duration of a closure, merged with the current
+    /// value if there is one.
+    ///
+    /// If the key is already set, `f` sees `merge(current, t)` instead of
+    /// `t`, which suits keys accumulating context, like sets of tags. The
+    /// outer value is restored afterwards either way.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static TAGS: Vec<&'static str>);
+    ///
+    /// # fn main() {
+    /// let merge = |a: &Vec<&'static str>, b: &Vec<&'static str>| [&a[..], &b[..]].concat();
+    /// TAGS.set_merged(&vec!["http"], merge, || {
+    ///     TAGS.set_merged(&vec!["auth"], merge, || {
+    ///         TAGS.with(|tags| assert_eq!(*tags, ["http", "auth"]));
+    ///     });
+    ///     TAGS.with(|tags| assert_eq!(*tags, ["http"]));
+    /// });
+    /// # }
+    /// ```
+    pub fn set_merged<F, R>(&'static self, t: &T, merge: impl FnOnce(&T, &T) -> T, f: F) -> R
+    where
+        T: Sized,
+        F: FnOnce() -> R,
+    {
+        match self.try_with(|current| merge(current, t)) {
+            Ok(merged) => self.set(&merged, f),
+            Err(_) => self.set(t, f),
+        }
+    }
+
+    /// Inserts a value for the duration of a closure, unless that would nest
+    /// this key more than `max` scopes deep.
+    ///
+    /// Nested scopes are counted since the innermost `suspend`, including the
+    /// new one. If the limit would be exceeded `f` isn't called, and the
+    /// error carries the depth which was attempted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// fn recurse(n: u32) -> u32 {
+    ///     match FOO.set_checked_depth(&n, 3, || recurse(n + 1)) {
+    ///         Ok(deepest) => deepest,
+    ///         Err(_) => n - 1,
+    ///     }
+    /// }
+    /// assert_eq!(recurse(1), 3);
+    /// # }
+    /// ```
+    pub fn set_checked_depth<F, R>(&'static self, t: &T, max: usize, f: F) -> Result<R, DepthError>
+    where
+        F: FnOnce() -> R,
+    {
+        let depth = self.depth() + 1;
+        if depth > max {
+            return Err(DepthError { depth, max });
+        }
+        Ok(self.set(t, f))
+    }
+
+    /// Like `set_checked_depth`, with the limit set by
+    /// [`set_default_depth_limit`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// scoped_tls::set_default_depth_limit(1);
+    /// FOO.set_limited(&1, || assert!(FOO.set_limited(&2, || {}).is_err()))
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn set_limited<F, R>(&'static self, t: &T, f: F) -> Result<R, DepthError>
+    where
+        F: FnOnce() -> R,
+    {
+        let max = DEFAULT_DEPTH_LIMIT.load(Ordering::Relaxed);
+        self.set_checked_depth(t, max, f)
+    }
+
+    /// Visits the values of the nested scopes of this key and `other`
+    /// pairwise, matching them up by depth starting from the outermost scope.
+    ///
+    /// If the keys are nested to different depths, only as many pairs as the
+    /// shallower key has scopes are visited; the innermost values of the
+    /// deeper key are skipped. Scopes hidden by `suspend` are not visited.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static NAME: &'static str);
+    /// scoped_thread_local!(static LINE: u32);
+    ///
+    /// # fn main() {
+    /// NAME.set(&"main", || LINE.set(&1, || {
+    ///     NAME.set(&"parse", || LINE.set(&7, || {
+    ///         let mut trace = Vec::new();
+    ///         NAME.zip_active(&LINE, |name, line| trace.push((*name, *line)));
+    ///         assert_eq!(trace, [("main", 1), ("parse", 7)]);
+    ///     }));
+    /// }));
+    /// # }
+    /// ```
+    pub fn zip_active<U: ?Sized, F>(&'static self, other: &'static ScopedKey<U>, mut f: F)
+    where
+        F: FnMut(&T, &U),
+    {
+        for (a, b) in self.stack().into_iter().zip(other.stack()) {
+            unsafe { f(a.as_ref(), b.as_ref()) }
+        }
+    }
+
+    /// Returns an iterator which calls `f` once per item of `items`, with the
+    /// key set to that item for the duration of the call.
+    ///
+    /// The iterator is lazy: each item is only installed while `next`
+    /// computes the corresponding output.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static ITEM: u32);
+    ///
+    /// # fn main() {
+    /// let doubled: Vec<u32> = ITEM.scoped_map(vec![1, 2, 3], || ITEM.get() * 2).collect();
+    /// assert_eq!(doubled, [2, 4, 6]);
+    /// # }
+    /// ```
+    pub fn scoped_map<I, U, F>(&'static self, items: I, mut f: F) -> impl Iterator<Item = U>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<T>,
+        F: FnMut() -> U,
+    {
+        items
+            .into_iter()
+            .map(move |item| self.set(item.borrow(), &mut f))
+    }
+
+    /// Returns where each visible scope of this key was set, outermost first.
+    ///
+    /// Locations are recorded by `set`, `set_debug` and `set_guard`; scopes
+    /// installed any other way are left out, as are scopes hidden by
+    /// `suspend`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     let locations = FOO.set_locations();
+    ///     assert_eq!(locations.len(), 1);
+    ///     assert_eq!(locations[0].file(), file!());
+    /// });
+    /// # }
+    /// ```
+    #[cfg(feature = "capture-locations")]
+    pub fn set_locations(&'static self) -> Vec<&'static Location<'static>> {
+        let mut locations = Vec::new();
+        let mut cur = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        while let Some(frame) = unsafe { cur.as_ref() } {
+            if frame.value.is_none() {
+                break;
+            }
+            locations.extend(frame.location);
+            cur = frame.prev.get();
+        }
+        locations.reverse();
+        locations
+    }
+
+    /// Returns the value `with` would observe, if any.
+    fn value(&'static self) -> Option<NonNull<T>> {
+        self.try_value().ok()
+    }
+
+    fn try_value(&'static self) -> Result<NonNull<T>, AccessError> {
+        match self.try_top() {
+            // Safety: frames in the list are alive until they unlink themselves
+            Ok(top) => match unsafe { top.as_ref() }.and_then(|frame| {
+                frame.mark_read();
+                frame.value
+            }) {
+                Some(value) => Ok(value),
+                None => self.default_value()?.ok_or(AccessError::Unset),
+            },
+            Err(e) => self.default_value().ok().flatten().ok_or(e),
+        }
+    }
+
+    /// Returns the value observed when no scope is set, if the key has one.
+    ///
+    /// Fails if the key's default was read from the environment and this
+    /// thread has already dropped it, rather than silently falling back to
+    /// the constant.
+    fn default_value(&'static self) -> Result<Option<NonNull<T>>, AccessError> {
+        let env = match self.env_default {
+            Some(env) => {
+                env.try_with(|d| d.0.as_deref().map(|v| v as *const dyn Any as *const ()))?
+            }
+            None => None,
+        };
+        Ok(env
+            // Safety: `new_with_env_default` checked that `*const T` is thin,
+            // and the macro always stores a `T` in the `EnvDefault`
+            .map(|ptr| unsafe { NonNull::new_unchecked(mem::transmute_copy(&ptr)) })
+            .or(self.default))
+    }
+
+    /// Returns the number of visible nested scopes.
+    fn depth(&'static self) -> usize {
+        // Safety: frames in the list are alive until they unlink themselves
+        unsafe { self.top().as_ref() }.map_or(0, |frame| frame.depth.get())
+    }
+
+    /// Returns the values of the visible scopes, outermost first.
+    fn stack(&'static self) -> Vec<NonNull<T>> {
+        let mut values = Vec::new();
+        let mut cur = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        while let Some(frame) = unsafe { cur.as_ref() } {
+            match frame.value {
+                Some(value) => {
+                    frame.mark_read();
+                    values.push(value);
+                }
+                None => break,
+            }
+            cur = frame.prev.get();
+        }
+        values.reverse();
+        values
+    }
+
+    /// Returns the innermost frame, or null if there is none. Once the thread
+    /// local has been destroyed there are no frames left.
+    fn top(&'static self) -> *const Frame<T> {
+        self.try_top().unwrap_or(ptr::null())
+    }
+
+    fn try_top(&'static self) -> Result<*const Frame<T>, AccessError> {
+        Ok(self.inner.try_with(|c| c.get() as *const Frame<T>)?)
+    }
+
+    /// Pushes `frame` as the innermost scope of this key.
+    ///
+    /// # Safety
+    /// `frame` must not move and must be passed to `unlink` before it is
+    /// dropped, on the same thread.
+    unsafe fn link(&'static self, frame: &Frame<T>) {
+        let prev = self.inner.try_with(|c| {
+            let prev = c.get() as *const Frame<T>;
+            frame.prev.set(prev);
+            frame.update_from_prev();
+            c.set(frame as *const Frame<T> as *const ());
+            prev
+        });
+        let prev = prev.unwrap_or_else(|_| {
+            panic!(
+                "cannot set scoped thread local variable `{}` during or after destruction",
+                self.display_name()
+            )
+        });
+        #[cfg(feature = "registry")]
+        {
+            if prev.is_null() {
+                registry::register(self);
+            }
+        }
+        #[cfg(not(feature = "registry"))]
+        let _ = prev;
+    }
+
+    /// Returns whether a scope with a value is linked inside `frame`.
+    ///
+    /// Suspended scopes may legitimately outlive the scope they were entered
+    /// in, so only scopes with values count.
+    #[cfg(debug_assertions)]
+    fn shadowed_by_value(&'static self, frame: &Frame<T>) -> bool {
+        let mut shadowed = false;
+        let mut cur = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        while let Some(f) = unsafe { cur.as_ref() } {
+            if ptr::eq(f, frame) {
+                return shadowed;
+            }
+            shadowed |= f.value.is_some();
+            cur = f.prev.get();
+        }
+        false
+    }
+
+    /// Panics if a new scope of this key may not be entered, because an
+    /// enclosing scope was fused or too many keys are set.
+    fn check_set(&'static self) {
+        #[cfg(feature = "registry")]
+        registry::check_active_keys(self);
+        // Safety: frames in the list are alive until they unlink themselves
+        if unsafe { self.top().as_ref() }.map_or(false, |top| top.fused.get()) {
+            panic!(
+                "cannot set scoped thread local variable `{}` after it has been fused",
+                self.display_name()
+            );
+        }
+    }
+
+    /// Returns a note to append to the message about accessing this key while
+    /// it is unset.
+    ///
+    /// Deployments can add their own guidance through the `SCOPED_TLS_HINT`
+    /// environment variable, which is only read once this is needed.
+    fn unset_hint(&'static self) -> String {
+        self.unset_hint_from("SCOPED_TLS_HINT")
+    }
+
+    /// Like `unset_hint`, but reads the guidance from the environment
+    /// variable `var`, so tests don't have to change `SCOPED_TLS_HINT` for
+    /// the whole process.
+    fn unset_hint_from(&'static self, var: &str) -> String {
+        #[cfg(feature = "debug-crossthread")]
+        let mut hint = crossthread::hint(self);
+        #[cfg(not(feature = "debug-crossthread"))]
+        let mut hint = String::new();
+        if let Some(env) = env::var_os(var) {
+            hint.push_str(" (");
+            hint.push_str(&env.to_string_lossy());
+            hint.push(')');
+        }
+        hint
+    }
+
+    /// Returns the name of this key in diagnostics, which `set_named` may
+    /// override.
+    fn display_name(&'static self) -> &'static str {
+        self.name_from(self.top())
+    }
+
+    /// Reports `frame` to `check_unused_scopes` if its value was never read.
+    #[cfg(debug_assertions)]
+    fn check_unused(&'static self, frame: &Frame<T>) {
+        if frame.value.is_none() || frame.read.get() || thread::panicking() {
+            return;
+        }
+        if let Ok(Some(report)) = UNUSED_SCOPE_REPORT.try_with(|r| r.get()) {
+            report(self.name_from(frame));
+        }
+    }
+
+    /// Returns the name in effect in `frame`'s scope.
+    fn name_from(&'static self, mut frame: *const Frame<T>) -> &'static str {
+        // Safety: frames in the list are alive until they unlink themselves
+        while let Some(f) = unsafe { frame.as_ref() } {
+            if let Some(name) = f.name {
+                return name;
+            }
+            frame = f.prev.get();
+        }
+        self.name
+    }
+
+    /// Called once a frame with a value has been linked for a new scope.
+    fn entered(&'static self) {
+        if VERSION_OBSERVED.load(Ordering::Relaxed) {
+            let _ = SCOPE_VERSION.try_with(|v| v.set(v.get() + 1));
+        }
+        #[cfg(feature = "debug-crossthread")]
+        crossthread::entered(self);
+        #[cfg(feature = "metrics")]
+        self.counters.set();
+        #[cfg(feature = "registry")]
+        {
+            if registry::has_hooks() {
+                registry::entered(self.display_name());
+            }
+        }
+    }
+
+    /// Runs the actions deferred to the exit of `frame`, then unlinks it.
+    fn exit(&'static self, frame: &Frame<T>) {
+        // Deferred actions are rare, so keep running them out of line.
+        if frame.deferred.is_empty() {
+            self.leave(frame);
+        } else {
+            self.exit_deferred(frame);
+        }
+    }
+
+    #[cold]
+    fn exit_deferred(&'static self, frame: &Frame<T>) {
+        struct Leave<'a, T: ?Sized + 'static> {
+            key: &'static ScopedKey<T>,
+            frame: &'a Frame<T>,
+        }
+        impl<T: ?Sized> Drop for Leave<'_, T> {
+            fn drop(&mut self) {
+                self.key.leave(self.frame);
+                // Any actions left over after one panicked are dropped
+                // without running.
+                self.frame.deferred.clear();
+            }
+        }
+        let _leave = Leave { key: self, frame };
+        while let Some(action) = frame.deferred.pop() {
+            action();
+        }
+    }
+
+    /// Unlinks `frame` once its deferred actions have run.
+    fn leave(&'static self, frame: &Frame<T>) {
+        // The name depends on the enclosing scopes, so it has to be resolved
+        // before unlinking, but only if a hook will use it.
+        #[cfg(feature = "registry")]
+        let name = if frame.value.is_some() && registry::has_hooks() {
+            Some(self.name_from(frame))
+        } else {
+            None
+        };
+        #[cfg(debug_assertions)]
+        self.check_unused(frame);
+        self.unlink(frame);
+        #[cfg(feature = "registry")]
+        {
+            if let Some(name) = name {
+                registry::exited(name);
+            }
+        }
+    }
+
+    /// Removes `frame` from the list of scopes.
+    ///
+    /// Scopes are normally exited innermost first, but guards may be dropped
+    /// in any order, so a frame is unlinked from wherever it sits.
+    ///
+    /// This runs from destructors, possibly while the thread's locals are
+    /// being torn down. If the thread local is already gone there is nothing
+    /// left which could observe the frame, so that isn't an error.
+    fn unlink(&'static self, frame: *const Frame<T>) {
+        let _ = self.inner.try_with(|c| {
+            if c.get() as *const Frame<T> == frame {
+                // Safety: frames in the list are alive until they unlink themselves
+                c.set(unsafe { (*frame).prev.get() } as *const ());
+            } else {
+                Self::unlink_nested(c, frame);
+            }
+        });
+    }
+
+    /// Unlinks `frame` from below the innermost scope.
+    #[cold]
+    fn unlink_nested(c: &Cell<*const ()>, frame: *const Frame<T>) {
+        // Safety: frames in the list are alive until they unlink themselves
+        unsafe {
+            let next = (*frame).prev.get();
+            let mut cur = c.get() as *const Frame<T>;
+            // The depths and fuses of the frames above `frame` may change.
+            let mut above = Vec::new();
+            while let Some(f) = cur.as_ref() {
+                above.push(f);
+                if f.prev.get() == frame {
+                    f.prev.set(next);
+                    for f in above.iter().rev() {
+                        f.update_from_prev();
+                    }
+                    return;
+                }
+                cur = f.prev.get();
+            }
+        }
+    }
+}
+
+impl<T: Copy> ScopedKey<T> {
+    /// Returns a copy of the value of this scoped variable.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || assert_eq!(FOO.get(), 1));
+    /// # }
+    /// ```
+    pub fn get(&'static self) -> T {
+        self.with(|v| *v)
+    }
+
+    /// Returns a copy of the value of this scoped variable.
+    ///
+    /// This is the same as `get`, for `Copy` enums matched on directly, as in
+    /// `match MODE.get_copy() { .. }`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    pub fn get_copy(&'static self) -> T {
+        self.get()
+    }
+}
+
+/// A value of a [`ScopedKey`] which can be sent to other threads and
+/// installed there.
+///
+/// Handed out by [`ScopedKey::set_shared`].
+pub struct SharedRef<'a, T: ?Sized + Sync + 'static> {
+    key: &'static ScopedKey<T>,
+    value: &'a T,
+}
+
+impl<T: ?Sized + Sync> Clone for SharedRef<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized + Sync> Copy for SharedRef<'_, T> {}
+
+impl<'a, T: ?Sized + Sync> SharedRef<'a, T> {
+    /// Returns the shared value.
+    pub fn get(&self) -> &'a T {
+        self.value
+    }
+
+    /// Installs the shared value in the key on the current thread for the
+    /// duration of `f`.
+    ///
+    /// This behaves exactly like [`ScopedKey::set`].
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    pub fn install<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.key.set(self.value, f)
+    }
+}
+
+/// An error returned by [`ScopedKey::try_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AccessError {
+    /// No value has been `set` for the key on this thread.
+    Unset,
+    /// The thread is exiting and the thread local storage backing the key
+    /// has already been destroyed.
+    Destroyed,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessError::Unset => f.write_str("scoped thread local variable is not set"),
+            AccessError::Destroyed => {
+                f.write_str("scoped thread local variable has already been destroyed")
+            }
+        }
+    }
+}
+
+impl Error for AccessError {}
+
+impl From<thread::AccessError> for AccessError {
+    fn from(_: thread::AccessError) -> AccessError {
+        AccessError::Destroyed
+    }
+}
+
+thread_local!(static SCOPE_VERSION: Cell<u64> = const { Cell::new(0) });
+
+/// Whether `global_scope_version` was ever called. Until then nothing can
+/// compare versions, so entering a scope doesn't need to bump it.
+static VERSION_OBSERVED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the number of scopes entered on the current thread, for any key,
+/// since this function was first called in the process.
+///
+/// Comparing two versions tells whether any scope was entered in between,
+/// see [`ScopedKey::with_at_version`].
+pub fn global_scope_version() -> u64 {
+    VERSION_OBSERVED.store(true, Ordering::Relaxed);
+    SCOPE_VERSION.with(|v| v.get())
+}
+
+static DEFAULT_DEPTH_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Sets how deeply the scopes of a key may be nested by
+/// [`ScopedKey::set_limited`], for all keys and threads.
+///
+/// This lets libraries rely on a limit configured once by the application
+/// rather than passing one to every `set_checked_depth`. Plain `set` is not
+/// affected. There is no limit by default.
+pub fn set_default_depth_limit(n: usize) {
+    DEFAULT_DEPTH_LIMIT.store(n, Ordering::Relaxed);
+}
+
+#[cfg(debug_assertions)]
+thread_local!(static UNUSED_SCOPE_REPORT: Cell<Option<fn(&'static str)>> = const {
+    Cell::new(None)
+});
+
+/// Makes scopes which are exited without their value having been read call
+/// `report` with the name of the key, on the current thread.
+///
+/// A value counts as read once `with`, `try_with` or `is_set` observed it, or
+/// a method like `defer`, `fuse`, `commit` or `next_local_id` used its scope.
+/// Scopes exited by a panic aren't reported. Pass `None` to stop checking,
+/// which is the default. The check only runs with debug assertions enabled;
+/// in release builds this does nothing.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static FOO: u32);
+///
+/// # fn main() {
+/// scoped_tls::check_unused_scopes(Some(|name| eprintln!("`{}` was set but never read", name)));
+/// FOO.set(&1, || {});
+/// # }
+/// ```
+pub fn check_unused_scopes(report: Option<fn(&'static str)>) {
+    #[cfg(debug_assertions)]
+    UNUSED_SCOPE_REPORT.with(|r| r.set(report));
+    #[cfg(not(debug_assertions))]
+    let _ = report;
+}
+
+/// An error returned by [`ScopedKey::set_checked_depth`] when the scopes of a
+/// key would be nested too deeply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepthError {
+    depth: usize,
+    max: usize,
+}
+
+impl DepthError {
+    /// The depth the new scope would have had.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The maximum depth which was allowed.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+
+impl fmt::Display for DepthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scope depth {} exceeds the limit of {}",
+            self.depth, self.max
+        )
+    }
+}
+
+impl Error for DepthError {}
+
+/// A single scope of a `ScopedKey`, linked to the scope it shadows.
+///
+/// The thread local of a key points at its innermost frame. A frame without
+/// a value hides everything beneath it, which is how `suspend` works.
+struct Frame<T: ?Sized> {
+    value: Option<NonNull<T>>,
+    /// Whether `value` was read, for `check_unused_scopes`.
+    #[cfg(debug_assertions)]
+    read: Cell<bool>,
+    prev: Cell<*const Frame<T>>,
+    /// The number of scopes with values up to and including this one, since
+    /// the last suspended scope.
+    depth: Cell<usize>,
+    deferred: Deferred,
+    /// Whether `fuse` was called in this scope.
+    fused_here: Cell<bool>,
+    /// Whether `fuse` was called in this scope or one it shadows.
+    fused: Cell<bool>,
+    /// The next id `next_local_id` hands out, for `set_with_counter` scopes.
+    counter: Option<Cell<u64>>,
+    /// The name given to the key by `set_named`.
+    name: Option<&'static str>,
+    /// The flag `commit` sets, for `set_txn` scopes.
+    txn: Option<NonNull<Cell<bool>>>,
+    #[cfg(feature = "registry")]
+    debug: Option<fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result>,
+    #[cfg(feature = "capture-locations")]
+    location: Option<&'static Location<'static>>,
+}
+
+/// The actions `defer` registered in a scope, allocated on first use.
+///
+/// Most scopes never defer anything, so they don't pay for a `Vec`. Every
+/// linked frame is exited, which frees the actions, so this is never dropped
+/// and frames don't need drop glue.
+struct Deferred(mem::ManuallyDrop<RefCell<Option<Vec<Action>>>>);
+
+type Action = Box<dyn FnOnce()>;
+
+impl Deferred {
+    #[inline]
+    fn new() -> Self {
+        Deferred(mem::ManuallyDrop::new(RefCell::new(None)))
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        RefCell::borrow(&self.0).is_none()
+    }
+
+    #[inline]
+    fn push(&self, action: Action) {
+        RefCell::borrow_mut(&self.0)
+            .get_or_insert_with(Vec::new)
+            .push(action);
+    }
+
+    // Actions may defer more actions, so this doesn't hold the borrow. The
+    // `Vec` is freed once it runs out.
+    fn pop(&self) -> Option<Action> {
+        let mut actions = RefCell::borrow_mut(&self.0);
+        let action = actions.as_mut().and_then(Vec::pop);
+        if action.is_none() {
+            *actions = None;
+        }
+        action
+    }
+
+    fn clear(&self) {
+        let actions = RefCell::borrow_mut(&self.0).take();
+        drop(actions);
+    }
+}
+
+impl<T: ?Sized> Frame<T> {
+    fn new(value: Option<NonNull<T>>) -> Self {
+        Frame {
+            value,
+            #[cfg(debug_assertions)]
+            read: Cell::new(false),
+            prev: Cell::new(ptr::null()),
+            depth: Cell::new(0),
+            deferred: Deferred::new(),
+            fused_here: Cell::new(false),
+            fused: Cell::new(false),
+            counter: None,
+            name: None,
+            txn: None,
+            #[cfg(feature = "registry")]
+            debug: None,
+            #[cfg(feature = "capture-locations")]
+            location: None,
+        }
+    }
+
+    /// Like `new`, but records the caller as the place this scope was set.
+    #[cfg_attr(feature = "capture-locations", track_caller)]
+    fn new_here(value: Option<NonNull<T>>) -> Self {
+        let frame = Frame::new(value);
+        #[cfg(feature = "capture-locations")]
+        let frame = Frame {
+            location: Some(Location::caller()),
+            ..frame
+        };
+        frame
+    }
+
+    /// Records that this scope was used, for `check_unused_scopes`.
+    fn mark_read(&self) {
+        #[cfg(debug_assertions)]
+        self.read.set(true);
+    }
+
+    /// Recomputes `depth` and `fused` from the frame this one shadows.
+    fn update_from_prev(&self) {
+        // Safety: frames in the list are alive until they unlink themselves
+        let prev = unsafe { self.prev.get().as_ref() };
+        let depth = match self.value {
+            Some(_) => prev.map_or(0, |prev| prev.depth.get()) + 1,
+            None => 0,
+        };
+        self.depth.set(depth);
+        self.fused
+            .set(self.fused_here.get() || prev.map_or(false, |prev| prev.fused.get()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        panic,
+        sync::mpsc::{channel, Sender},
+        thread,
+    };
+
+    use crate::AccessError;
+
+    scoped_thread_local!(static FOO: u32);
+
+    /// Serializes the tests which change process-wide settings.
+    pub(crate) fn lock_globals() -> std::sync::MutexGuard<'static, ()> {
+        static LOCK: crate::Lazy<std::sync::Mutex<()>> = crate::Lazy::new();
+        LOCK.get(Default::default)
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    #[test]
+    fn smoke() {
+        scoped_thread_local!(static BAR: u32);
+
+        assert!(!BAR.is_set());
+        BAR.set(&1, || {
+            assert!(BAR.is_set());
+            BAR.with(|slot| {
+                assert_eq!(*slot, 1);
+            });
+        });
+        assert!(!BAR.is_set());
+    }
+
+    #[test]
+    fn cell_allowed() {
+        scoped_thread_local!(static BAR: Cell<u32>);
+
+        BAR.set(&Cell::new(1), || {
+            BAR.with(|slot| {
+                assert_eq!(slot.get(), 1);
+            });
+        });
+    }
+
+    #[test]
+    fn scope_item_allowed() {
+        assert!(!FOO.is_set());
+        FOO.set(&1, || {
+            assert!(FOO.is_set());
+            FOO.with(|slot| {
+                assert_eq!(*slot, 1);
+            });
+        });
+        assert!(!FOO.is_set());
+    }
+
+    #[test]
+    fn panic_resets() {
+        struct Check(Sender<u32>);
+        impl Drop for Check {
+            fn drop(&mut self) {
+                FOO.with(|r| {
+                    self.0.send(*r).unwrap();
+                })
+            }
+        }
+
+        let (tx, rx) = channel();
+        let t = thread::spawn(|| {
+            FOO.set(&1, || {
+                let _r = Check(tx);
+
+                FOO.set(&2, || panic!());
+            });
+        });
+
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert!(t.join().is_err());
+    }
+
+    #[test]
+    fn set_during_thread_teardown() {
+        struct SetOnDrop(Sender<u32>);
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                let val = FOO.set(&3, || FOO.get());
+                self.0.send(val).unwrap();
+            }
+        }
+        thread_local!(static LOCAL: RefCell<Option<SetOnDrop>> = const { RefCell::new(None) });
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            // `SetOnDrop` runs while the thread's locals are destroyed. The
+            // thread local of a key holds a plain pointer and has no
+            // destructor, so it is still usable here: this covers setting a
+            // key from another local's destructor, not exiting a scope after
+            // the key's own storage is gone, which can't be provoked.
+            LOCAL.with(|l| *l.borrow_mut() = Some(SetOnDrop(tx)));
+        })
+        .join()
+        .unwrap();
+        assert_eq!(rx.recv().unwrap(), 3);
+    }
+
+    #[test]
+    fn try_with() {
+        assert_eq!(FOO.try_with(|v| *v), Err(AccessError::Unset));
+        FOO.set(&1, || {
+            assert_eq!(FOO.try_with(|v| *v), Ok(1));
+            let _guard = FOO.suspend();
+            assert_eq!(FOO.try_with(|v| *v), Err(AccessError::Unset));
+        });
+    }
+
+    #[test]
+    fn destroyed_access_error() {
+        // Accessing a thread local from its own destructor fails, which maps
+        // to `AccessError::Destroyed`.
+        struct Reentrant(Sender<AccessError>);
+        impl Drop for Reentrant {
+            fn drop(&mut self) {
+                let err = LOCAL.try_with(|_| ()).unwrap_err();
+                self.0.send(err.into()).unwrap();
+            }
+        }
+        thread_local!(static LOCAL: RefCell<Option<Reentrant>> = const { RefCell::new(None) });
+
+        let (tx, rx) = channel();
+        thread::spawn(move || LOCAL.with(|l| *l.borrow_mut() = Some(Reentrant(tx))))
+            .join()
+            .unwrap();
+        let err = rx.recv().unwrap();
+        assert_eq!(err, AccessError::Destroyed);
+        assert_eq!(
+            err.to_string(),
+            "scoped thread local variable has already been destroyed"
+        );
+    }
+
+    // Destructors run in reverse order of registration on Linux, which the
+    // test below relies on to observe a destroyed default.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn try_with_from_destructor() {
+        // The storage of a key itself has no destructor, so only a default
+        // read from the environment can be gone by the time a thread local
+        // destructor runs.
+        scoped_thread_local!(static ENV: u32 = env "SCOPED_TLS_TEST_DESTROYED" or const 1);
+
+        struct Probe(Sender<(Result<u32, AccessError>, Result<u32, AccessError>)>);
+        impl Drop for Probe {
+            fn drop(&mut self) {
+                let set = FOO.set(&2, || FOO.try_with(|v| *v));
+                self.0.send((set, ENV.try_with(|v| *v))).unwrap();
+            }
+        }
+        thread_local!(static LOCAL: RefCell<Option<Probe>> = const { RefCell::new(None) });
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            LOCAL.with(|l| *l.borrow_mut() = Some(Probe(tx)));
+            // Registered after `LOCAL`, so this is dropped first.
+            assert_eq!(ENV.with(|v| *v), 1);
+        })
+        .join()
+        .unwrap();
+        let (set, env) = rx.recv().unwrap();
+        assert_eq!(set, Ok(2));
+        assert_eq!(env, Err(AccessError::Destroyed));
+    }
+
+    #[test]
+    fn attrs_allowed() {
+        scoped_thread_local!(
+            /// Docs
+            static BAZ: u32
+        );
+
+        scoped_thread_local!(
+            #[allow(non_upper_case_globals)]
+            static quux: u32
+        );
+
+        let _ = BAZ;
+        let _ = quux;
+    }
+
+    #[test]
+    fn const_default() {
+        scoped_thread_local!(static BAR: u32 = const 42);
+
+        assert!(BAR.is_set());
+        assert_eq!(BAR.with(|v| *v), 42);
+        BAR.set(&1, || {
+            assert_eq!(BAR.with(|v| *v), 1);
+            BAR.set(&2, || assert_eq!(BAR.with(|v| *v), 2));
+            assert_eq!(BAR.with(|v| *v), 1);
+        });
+        assert_eq!(BAR.with(|v| *v), 42);
+        thread::spawn(|| assert_eq!(BAR.with(|v| *v), 42))
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn env_default() {
+        scoped_thread_local!(static PARSED: u8 = env "SCOPED_TLS_TEST_PARSED" or const 3);
+        scoped_thread_local!(static ABSENT: u8 = env "SCOPED_TLS_TEST_ABSENT" or const 3);
+        scoped_thread_local!(static GARBAGE: u8 = env "SCOPED_TLS_TEST_GARBAGE" or const 3);
+
+        std::env::set_var("SCOPED_TLS_TEST_PARSED", "7");
+        std::env::set_var("SCOPED_TLS_TEST_GARBAGE", "loud");
+        assert_eq!(PARSED.with(|v| *v), 7);
+        assert_eq!(ABSENT.with(|v| *v), 3);
+        assert_eq!(GARBAGE.with(|v| *v), 3);
+        PARSED.set(&1, || assert_eq!(PARSED.with(|v| *v), 1));
+        assert_eq!(PARSED.with(|v| *v), 7);
+    }
+
+    #[test]
+    fn suspend() {
+        FOO.set(&1, || {
+            let guard = FOO.suspend();
+            assert!(!FOO.is_set());
+            FOO.set(&2, || assert_eq!(FOO.with(|v| *v), 2));
+            assert!(!FOO.is_set());
+            drop(guard);
+            assert!(FOO.is_set());
+            assert_eq!(FOO.with(|v| *v), 1);
+        });
+        assert!(!FOO.is_set());
+    }
+
+    #[test]
+    fn zip_active() {
+        scoped_thread_local!(static NAME: &'static str);
+        scoped_thread_local!(static SPAN: (u32, u32));
+
+        let zipped = || {
+            let mut pairs = Vec::new();
+            NAME.zip_active(&SPAN, |name, span| pairs.push((*name, *span)));
+            pairs
+        };
+        assert_eq!(zipped(), []);
+        NAME.set(&"a", || {
+            SPAN.set(&(0, 10), || {
+                NAME.set(&"b", || {
+                    SPAN.set(&(2, 8), || {
+                        NAME.set(&"c", || {
+                            // `SPAN` is one level shallower here
+                            assert_eq!(zipped(), [("a", (0, 10)), ("b", (2, 8))]);
+                            SPAN.set(&(3, 4), || {
+                                assert_eq!(
+                                    zipped(),
+                                    [("a", (0, 10)), ("b", (2, 8)), ("c", (3, 4))]
+                                );
+                            });
+                        });
+                    });
+                });
+            });
+        });
+    }
+
+    #[test]
+    fn with_or_install() {
+        let seen = FOO.with_or_install(&5, |v| {
+            assert_eq!(*v, 5);
+            FOO.with(|nested| *nested)
+        });
+        assert_eq!(seen, 5);
+        assert!(!FOO.is_set());
+
+        FOO.set(&1, || {
+            let seen = FOO.with_or_install(&5, |v| {
+                assert_eq!(*v, 1);
+                FOO.with(|nested| *nested)
+            });
+            assert_eq!(seen, 1);
+        });
+    }
+
+    #[test]
+    fn with_or_init() {
+        let mut inits = 0;
+        let seen = FOO.with_or_init(
+            || {
+                inits += 1;
+                7
+            },
+            |v| (*v, FOO.get()),
+        );
+        assert_eq!(seen, (7, 7));
+        assert_eq!(inits, 1);
+        assert!(!FOO.is_set());
 
-    scoped_thread_local!(static FOO: u32);
+        FOO.set(&1, || {
+            let seen = FOO.with_or_init(|| panic!("already set"), |v| *v);
+            assert_eq!(seen, 1);
+        });
+    }
 
     #[test]
-    fn smoke() {
-        scoped_thread_local!(static BAR: u32);
+    fn set_checked_depth() {
+        let res = FOO.set_checked_depth(&1, 2, || {
+            assert_eq!(FOO.depth(), 1);
+            FOO.set_checked_depth(&2, 2, || {
+                assert_eq!(FOO.get(), 2);
+                let err = FOO.set_checked_depth(&3, 2, || unreachable!()).unwrap_err();
+                assert_eq!((err.depth(), err.max()), (3, 2));
+                assert_eq!(err.to_string(), "scope depth 3 exceeds the limit of 2");
+                assert_eq!(FOO.get(), 2);
+            })
+        });
+        assert_eq!(res, Ok(Ok(())));
+        assert!(!FOO.is_set());
+        assert_eq!(FOO.depth(), 0);
+    }
 
-        assert!(!BAR.is_set());
-        BAR.set(&1, || {
-            assert!(BAR.is_set());
-            BAR.with(|slot| {
-                assert_eq!(*slot, 1);
+    #[test]
+    fn set_if_changed() {
+        FOO.set_if_changed(&1, || {
+            assert_eq!(FOO.depth(), 1);
+            FOO.set_if_changed(&1, || assert_eq!(FOO.depth(), 1));
+            FOO.set_if_changed(&2, || {
+                assert_eq!(FOO.depth(), 2);
+                assert_eq!(FOO.get(), 2);
             });
+            assert_eq!(FOO.depth(), 1);
+            assert_eq!(FOO.get(), 1);
         });
-        assert!(!BAR.is_set());
+        assert!(!FOO.is_set());
     }
 
     #[test]
-    fn cell_allowed() {
-        scoped_thread_local!(static BAR: Cell<u32>);
+    fn depth_after_unordered_drops() {
+        let (one, two, three) = (1, 2, 3);
+        let a = unsafe { FOO.set_guard(&one) };
+        let b = unsafe { FOO.set_guard(&two) };
+        let c = unsafe { FOO.set_guard(&three) };
+        assert_eq!(FOO.depth(), 3);
+        drop(b);
+        assert_eq!(FOO.depth(), 2);
+        assert_eq!(FOO.get(), 3);
+        let hole = FOO.suspend();
+        FOO.set(&4, || assert_eq!(FOO.depth(), 1));
+        drop(a);
+        FOO.set(&4, || assert_eq!(FOO.depth(), 1));
+        drop(hole);
+        assert_eq!(FOO.depth(), 1);
+        drop(c);
+        assert_eq!(FOO.depth(), 0);
+    }
 
-        BAR.set(&Cell::new(1), || {
-            BAR.with(|slot| {
-                assert_eq!(slot.get(), 1);
+    #[test]
+    fn transform() {
+        fn incr(n: u32) -> u32 {
+            n + 1
+        }
+
+        FOO.set(&1, || {
+            assert_eq!(FOO.transform(&[]), 1);
+            assert_eq!(FOO.transform(&[incr, incr]), 3);
+            assert_eq!(FOO.get(), 1);
+        });
+    }
+
+    #[test]
+    fn with_shadow_info() {
+        let info = || FOO.with_shadow_info(|v, shadows| (*v, shadows));
+        FOO.set(&1, || {
+            assert_eq!(info(), (1, false));
+            FOO.set(&2, || {
+                assert_eq!(info(), (2, true));
+                FOO.set(&3, || assert_eq!(info(), (3, true)));
+                // A suspended scope isn't observable, so isn't shadowed.
+                let _guard = FOO.suspend();
+                FOO.set(&4, || assert_eq!(info(), (4, false)));
             });
+            assert_eq!(info(), (1, false));
         });
     }
 
     #[test]
-    fn scope_item_allowed() {
+    fn defer() {
+        thread_local!(static LOG: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) });
+
+        fn log(n: u32) {
+            LOG.with(|l| l.borrow_mut().push(n));
+        }
+        fn take_log() -> Vec<u32> {
+            LOG.with(|l| l.borrow_mut().split_off(0))
+        }
+
+        FOO.set(&1, || {
+            FOO.defer(|| log(FOO.get()));
+            FOO.set(&2, || {
+                FOO.defer(|| log(20));
+                FOO.defer(|| log(21));
+            });
+            assert_eq!(take_log(), [21, 20]);
+            FOO.defer(|| log(10));
+        });
+        assert_eq!(take_log(), [10, 1]);
+
+        let res = panic::catch_unwind(|| {
+            FOO.set(&3, || {
+                FOO.defer(|| log(30));
+                FOO.defer(|| log(31));
+                panic!();
+            });
+        });
+        assert!(res.is_err());
+        assert_eq!(take_log(), [31, 30]);
+        assert!(!FOO.is_set());
+
+        // The actions left after one panics are dropped without running.
+        struct LogOnDrop(u32);
+        impl Drop for LogOnDrop {
+            fn drop(&mut self) {
+                log(self.0);
+            }
+        }
+        let res = panic::catch_unwind(|| {
+            FOO.set(&4, || {
+                let pending = LogOnDrop(40);
+                FOO.defer(move || log(pending.0 + 1));
+                FOO.defer(|| panic!());
+            });
+        });
+        assert!(res.is_err());
+        assert_eq!(take_log(), [40]);
+        assert!(!FOO.is_set());
+    }
+
+    #[test]
+    fn suspend_outliving_scope() {
+        let mut guard = None;
+        FOO.set(&1, || guard = Some(FOO.suspend()));
+        assert!(!FOO.is_set());
+        drop(guard);
         assert!(!FOO.is_set());
+    }
+
+    #[test]
+    #[cfg(feature = "capture-locations")]
+    fn set_locations() {
+        let outer = line!() + 1;
         FOO.set(&1, || {
-            assert!(FOO.is_set());
-            FOO.with(|slot| {
-                assert_eq!(*slot, 1);
+            let inner = line!() + 1;
+            FOO.set(&2, || {
+                let lines: Vec<u32> = FOO.set_locations().iter().map(|l| l.line()).collect();
+                assert_eq!(lines, [outer, inner]);
+                assert!(FOO.set_locations().iter().all(|l| l.file() == file!()));
             });
+            let _suspended = FOO.suspend();
+            assert!(FOO.set_locations().is_empty());
         });
+        assert!(FOO.set_locations().is_empty());
+    }
+
+    #[test]
+    fn fuse() {
+        FOO.set(&1, || {
+            FOO.fuse();
+            let nested = panic::catch_unwind(|| FOO.set(&2, || {}));
+            assert!(nested.is_err());
+            assert!(panic::catch_unwind(|| unsafe { FOO.set_guard(&2) }).is_err());
+            assert_eq!(FOO.get(), 1);
+            let suspended = FOO.suspend();
+            assert!(panic::catch_unwind(|| FOO.set(&2, || {})).is_err());
+            drop(suspended);
+        });
+        FOO.set(&2, || FOO.set(&3, || assert_eq!(FOO.get(), 3)));
+
+        // A suspension outliving the fused scope no longer inherits the fuse.
+        let mut suspended = None;
+        FOO.set(&1, || {
+            FOO.fuse();
+            suspended = Some(FOO.suspend());
+        });
+        FOO.set(&2, || {});
+        drop(suspended);
+    }
+
+    #[test]
+    fn is() {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        enum Mode {
+            Fast,
+            Safe,
+        }
+        scoped_thread_local!(static MODE: Mode);
+
+        assert!(!MODE.is(|_| true));
+        MODE.set(&Mode::Fast, || {
+            assert!(MODE.is(|m| *m == Mode::Fast));
+            assert!(!MODE.is(|m| *m == Mode::Safe));
+            match MODE.get_copy() {
+                Mode::Fast => {}
+                Mode::Safe => panic!("wrong mode"),
+            }
+        });
+        assert!(!MODE.is(|_| true));
+    }
+
+    #[test]
+    #[cfg(feature = "timing")]
+    fn with_timed() {
+        FOO.set(&3, || {
+            let (r, elapsed) = FOO.with_timed(|v| {
+                thread::sleep(std::time::Duration::from_millis(1));
+                *v * 2
+            });
+            assert_eq!(r, 6);
+            assert!(elapsed >= std::time::Duration::from_millis(1));
+        });
+    }
+
+    #[test]
+    fn mirror() {
+        use crate::ScopedKey;
+        scoped_thread_local!(static DST: u32);
+
+        DST.set(&1, || {
+            FOO.set(&2, || {
+                ScopedKey::mirror(&FOO, &DST, || {
+                    assert_eq!(DST.get(), 2);
+                    FOO.with(|a| DST.with(|b| assert!(std::ptr::eq(a, b))));
+                });
+            });
+            assert_eq!(DST.get(), 1);
+        });
+        assert!(!DST.is_set());
+    }
+
+    #[test]
+    fn set_raw() {
+        let val = 4;
+        unsafe { FOO.set_raw(&val, || assert_eq!(FOO.get(), 4)) };
+        assert!(panic::catch_unwind(|| unsafe { FOO.set_raw(std::ptr::null(), || {}) }).is_err());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "misaligned")]
+    fn set_raw_misaligned() {
+        let vals = [0u32; 2];
+        let ptr = (vals.as_ptr() as *const u8).wrapping_add(1) as *const u32;
+        unsafe { FOO.set_raw(ptr, || {}) };
+    }
+
+    #[test]
+    fn scoped_map() {
+        let mut iter = FOO.scoped_map(&[1, 2, 3], || FOO.get() + 10);
+        assert!(!FOO.is_set());
+        assert_eq!(iter.next(), Some(11));
         assert!(!FOO.is_set());
+        assert_eq!(iter.collect::<Vec<_>>(), [12, 13]);
+
+        scoped_thread_local!(static NAME: str);
+        let names = vec![String::from("a"), String::from("bc")];
+        let lens: Vec<usize> = NAME.scoped_map(names, || NAME.with(|n| n.len())).collect();
+        assert_eq!(lens, [1, 2]);
     }
 
     #[test]
-    fn panic_resets() {
-        struct Check(Sender<u32>);
-        impl Drop for Check {
+    fn set_checked() {
+        scoped_thread_local!(static COUNT: Cell<u32>);
+        let even = |c: &Cell<u32>| c.get() % 2 == 0;
+
+        let count = Cell::new(0);
+        COUNT.set_checked(&count, even, || COUNT.with(|c| c.set(c.get() + 2)));
+        assert_eq!(count.get(), 2);
+
+        let err = panic::catch_unwind(|| {
+            let count = Cell::new(0);
+            COUNT.set_checked(&count, even, || COUNT.with(|c| c.set(c.get() + 1)));
+        })
+        .unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(
+            msg.contains("invariant of scoped thread local variable `COUNT`"),
+            "{}",
+            msg
+        );
+        assert!(!COUNT.is_set());
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Config {
+        name: String,
+    }
+
+    scoped_context! {
+        CONFIG: Config => config;
+    }
+    scoped_context! {
+        pub(crate) LEVEL: u32 => level;
+    }
+
+    #[test]
+    fn scoped_context() {
+        assert!(!config::is_set());
+        assert_eq!(level::try_get(), None);
+        let cfg = Config {
+            name: String::from("test"),
+        };
+        config::set(&cfg, || {
+            level::set(&3, || {
+                assert_eq!(config::cloned(), cfg);
+                assert_eq!(config::with(|c| c.name.len()), 4);
+                assert_eq!(level::get(), 3);
+                assert_eq!(level::try_get(), Some(3));
+                assert_eq!(LEVEL.get(), 3);
+            });
+        });
+        assert_eq!(config::try_with(|_| ()), Err(AccessError::Unset));
+    }
+
+    #[test]
+    fn next_local_id() {
+        FOO.set_with_counter(&1, || {
+            assert_eq!(FOO.next_local_id(), 0);
+            FOO.set_with_counter(&2, || {
+                assert_eq!(FOO.next_local_id(), 0);
+                assert_eq!(FOO.next_local_id(), 1);
+            });
+            // Plain scopes use the counter of the enclosing one.
+            FOO.set(&3, || assert_eq!(FOO.next_local_id(), 1));
+            assert_eq!(FOO.next_local_id(), 2);
+            let _suspended = FOO.suspend();
+            assert!(panic::catch_unwind(|| FOO.next_local_id()).is_err());
+        });
+        FOO.set_with_counter(&1, || assert_eq!(FOO.next_local_id(), 0));
+        assert!(panic::catch_unwind(|| FOO.next_local_id()).is_err());
+    }
+
+    #[test]
+    fn set_cf() {
+        use std::ops::ControlFlow;
+
+        FOO.set(&1, || {
+            let flow = FOO.set_cf(&2, || ControlFlow::<(), u32>::Continue(FOO.get()));
+            assert_eq!(flow, ControlFlow::Continue(2));
+            assert_eq!(FOO.get(), 1);
+            let flow = FOO.set_cf(&3, || ControlFlow::<u32, ()>::Break(FOO.get()));
+            assert_eq!(flow, ControlFlow::Break(3));
+            assert_eq!(FOO.get(), 1);
+        });
+    }
+
+    #[test]
+    fn set_assert_no_panic() {
+        assert_eq!(FOO.set_assert_no_panic(&1, || FOO.get()), 1);
+
+        let err = panic::catch_unwind(|| FOO.set_assert_no_panic(&1, || panic!("code {}", 7)))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<String>().map(|s| &s[..]),
+            Some("panicked inside a scope of scoped thread local variable `FOO`: code 7")
+        );
+        assert!(!FOO.is_set());
+
+        let err = panic::catch_unwind(|| FOO.set_assert_no_panic(&1, || panic::panic_any(3u8)))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<u8>(), Some(&3));
+    }
+
+    #[test]
+    fn with_as_debug() {
+        use std::fmt::Write;
+
+        let mut s = String::new();
+        FOO.set(&7, || FOO.with_as_debug(|v| write!(s, "[{:?}]", v)))
+            .unwrap();
+        assert_eq!(s, "[7]");
+    }
+
+    #[test]
+    fn try_with_depth() {
+        assert_eq!(FOO.try_with_depth(|_, depth| depth), None);
+        FOO.set(&1, || {
+            assert_eq!(FOO.try_with_depth(|v, depth| (*v, depth)), Some((1, 1)));
+            FOO.set(&2, || {
+                assert_eq!(FOO.try_with_depth(|v, depth| (*v, depth)), Some((2, 2)));
+                assert_eq!(FOO.with_depth(|_, depth| depth), 2);
+            });
+        });
+
+        scoped_thread_local!(static DEFAULTED: u32 = const 0);
+        assert_eq!(
+            DEFAULTED.try_with_depth(|v, depth| (*v, depth)),
+            Some((0, 0))
+        );
+    }
+
+    #[test]
+    fn with_chain() {
+        scoped_thread_local!(static REQUEST: u32);
+        scoped_thread_local!(static SESSION: u32);
+        scoped_thread_local!(static GLOBAL: u32);
+        let chain = || REQUEST.try_with_chain(&[&SESSION, &GLOBAL], |v| *v);
+
+        assert_eq!(chain(), None);
+        assert!(panic::catch_unwind(|| REQUEST.with_chain(&[&SESSION], |_| ())).is_err());
+        GLOBAL.set(&3, || {
+            assert_eq!(chain(), Some(3));
+            SESSION.set(&2, || {
+                assert_eq!(chain(), Some(2));
+                REQUEST.set(&1, || {
+                    assert_eq!(chain(), Some(1));
+                    assert_eq!(REQUEST.with_chain(&[&SESSION, &GLOBAL], |v| *v), 1);
+                });
+            });
+            assert_eq!(REQUEST.with_chain(&[&SESSION, &GLOBAL], |v| *v), 3);
+        });
+    }
+
+    #[test]
+    fn set_default_depth_limit() {
+        struct Restore;
+        impl Drop for Restore {
             fn drop(&mut self) {
-                FOO.with(|r| {
-                    self.0.send(*r).unwrap();
-                })
+                crate::set_default_depth_limit(usize::MAX);
             }
         }
+        scoped_thread_local!(static LIMITED: u32);
 
-        let (tx, rx) = channel();
-        let t = thread::spawn(|| {
-            FOO.set(&1, || {
-                let _r = Check(tx);
+        let _lock = lock_globals();
+        let _restore = Restore;
+        crate::set_default_depth_limit(2);
+        let third = LIMITED.set_limited(&1, || {
+            LIMITED
+                .set_limited(&2, || LIMITED.set_limited(&3, || {}))
+                .unwrap()
+        });
+        assert_eq!(third.unwrap().unwrap_err().depth(), 3);
+        LIMITED.set(&1, || LIMITED.set(&2, || LIMITED.set(&3, || {})));
+    }
 
-                FOO.set(&2, || panic!());
+    #[test]
+    fn with_at_version() {
+        FOO.set(&1, || {
+            let version = crate::global_scope_version();
+            assert_eq!(FOO.with_at_version(version, |v| *v), 1);
+            FOO.set(&2, || {});
+            let err = panic::catch_unwind(|| FOO.with_at_version(version, |_| ())).unwrap_err();
+            let msg = err.downcast_ref::<String>().unwrap();
+            assert!(msg.contains("differs from the expected"), "{}", msg);
+            assert_eq!(crate::global_scope_version(), version + 1);
+        });
+    }
+
+    #[test]
+    fn set_merged() {
+        let add = |a: &u32, b: &u32| a + b;
+        FOO.set_merged(&1, add, || {
+            assert_eq!(FOO.get(), 1);
+            FOO.set_merged(&2, add, || {
+                assert_eq!(FOO.get(), 3);
+                FOO.set_merged(&3, add, || assert_eq!(FOO.get(), 6));
             });
+            assert_eq!(FOO.get(), 1);
         });
+        assert!(!FOO.is_set());
+    }
 
-        assert_eq!(rx.recv().unwrap(), 1);
-        assert!(t.join().is_err());
+    #[test]
+    fn set_named() {
+        fn unset_message() -> String {
+            let err = panic::catch_unwind(|| FOO.with(|_| ())).unwrap_err();
+            err.downcast_ref::<String>().unwrap().clone()
+        }
+
+        assert!(unset_message().contains("`FOO`"));
+        FOO.set_named("renamed", &1, || {
+            assert_eq!(FOO.get(), 1);
+            FOO.set(&2, || {
+                let _suspended = FOO.suspend();
+                let msg = unset_message();
+                assert!(msg.contains("`renamed`"), "{}", msg);
+            });
+        });
+        assert!(unset_message().contains("`FOO`"));
     }
 
     #[test]
-    fn attrs_allowed() {
-        scoped_thread_local!(
-            /// Docs
-            static BAZ: u32
-        );
+    fn env_hint() {
+        scoped_thread_local!(static HINTED: u32);
+        let message = || {
+            let err = panic::catch_unwind(|| HINTED.with(|_| ())).unwrap_err();
+            err.downcast_ref::<String>().unwrap().clone()
+        };
 
-        scoped_thread_local!(
-            #[allow(non_upper_case_globals)]
-            static quux: u32
+        // Only this test uses this variable, so setting it doesn't affect
+        // tests running in parallel.
+        std::env::set_var("SCOPED_TLS_TEST_HINT", "see the runbook");
+        let hint = HINTED.unset_hint_from("SCOPED_TLS_TEST_HINT");
+        assert!(hint.ends_with(" (see the runbook)"), "{}", hint);
+        let hint = HINTED.unset_hint_from("SCOPED_TLS_TEST_MISSING_HINT");
+        assert!(!hint.contains("runbook"), "{}", hint);
+
+        let unhinted = message();
+        assert!(
+            unhinted.contains("without calling `set` first"),
+            "{}",
+            unhinted
         );
+        assert!(unhinted.ends_with(&HINTED.unset_hint()), "{}", unhinted);
+    }
 
-        let _ = BAZ;
-        let _ = quux;
+    #[test]
+    fn with_as_any() {
+        assert_eq!(FOO.with_as_any(|_| ()), None);
+        FOO.set(&5, || {
+            assert_eq!(
+                FOO.with_as_any(|v| v.downcast_ref::<u32>().copied()),
+                Some(Some(5))
+            );
+            assert_eq!(FOO.with_as_any(|v| v.is::<u64>()), Some(false));
+        });
+    }
+
+    #[test]
+    fn with_hash() {
+        use std::{
+            collections::hash_map::DefaultHasher,
+            hash::{Hash, Hasher},
+        };
+
+        #[derive(Hash)]
+        struct Request {
+            path: &'static str,
+            retries: u32,
+        }
+
+        scoped_thread_local!(static REQUEST: Request);
+
+        let request = Request {
+            path: "/index",
+            retries: 2,
+        };
+        let mut expected = DefaultHasher::new();
+        request.hash(&mut expected);
+        let expected = expected.finish();
+        REQUEST.set(&request, || {
+            let first = REQUEST.with_hash(&mut DefaultHasher::new(), |h| h);
+            let second = REQUEST.with_hash(&mut DefaultHasher::new(), |h| h);
+            assert_eq!(first, expected);
+            assert_eq!(second, expected);
+        });
+    }
+
+    #[test]
+    fn with_or_global_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static INITS: AtomicUsize = AtomicUsize::new(0);
+        scoped_thread_local!(static LIMIT: Vec<u32>);
+
+        fn init() -> Vec<u32> {
+            INITS.fetch_add(1, Ordering::SeqCst);
+            vec![1, 2]
+        }
+
+        let here = LIMIT.with_or_global_once(init, |v| v.as_ptr() as usize);
+        let there = thread::spawn(|| LIMIT.with_or_global_once(init, |v| v.as_ptr() as usize))
+            .join()
+            .unwrap();
+        assert_eq!(here, there);
+        assert_eq!(INITS.load(Ordering::SeqCst), 1);
+        LIMIT.set(&vec![3], || {
+            assert_eq!(LIMIT.with_or_global_once(init, |v| v.clone()), [3]);
+        });
+        assert_eq!(LIMIT.with_or_global_once(init, |v| v.clone()), [1, 2]);
+        assert_eq!(INITS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn set_shared() {
+        use std::sync::Mutex;
+
+        scoped_thread_local!(static LOG: Mutex<Vec<u32>>);
+
+        let log: &'static Mutex<Vec<u32>> = Box::leak(Box::new(Mutex::new(Vec::new())));
+        LOG.set_shared(log, |shared| {
+            let child = thread::spawn(move || {
+                assert!(!LOG.is_set());
+                shared.install(|| LOG.with(|log| log.lock().unwrap().push(1)));
+                assert!(!LOG.is_set());
+            });
+            child.join().unwrap();
+            LOG.with(|log| log.lock().unwrap().push(2));
+        });
+        assert_eq!(*log.lock().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn check_unused_scopes() {
+        thread_local!(static UNUSED: RefCell<Vec<&'static str>> = const {
+            RefCell::new(Vec::new())
+        });
+
+        scoped_thread_local!(static READ: u32);
+        scoped_thread_local!(static UNREAD: u32);
+
+        thread::spawn(|| {
+            crate::check_unused_scopes(Some(|name| UNUSED.with(|u| u.borrow_mut().push(name))));
+            READ.set(&1, || assert_eq!(READ.get(), 1));
+            READ.set(&1, || assert!(READ.is_set()));
+            UNREAD.set(&2, || {});
+            UNREAD.set(&2, || {
+                // Only reads in the scope itself count.
+                UNREAD.set(&3, || assert!(UNREAD.is_set()));
+            });
+            let _ = panic::catch_unwind(|| UNREAD.set(&4, || panic!()));
+            assert_eq!(UNUSED.with(|u| u.borrow().clone()), ["UNREAD", "UNREAD"]);
+        })
+        .join()
+        .unwrap();
+
+        thread::spawn(|| {
+            // Nothing is reported until a thread asks for it.
+            assert!(super::UNUSED_SCOPE_REPORT.with(Cell::get).is_none());
+            UNREAD.set(&1, || {});
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn check_unused_scopes_helpers() {
+        thread_local!(static UNUSED: RefCell<Vec<&'static str>> = const {
+            RefCell::new(Vec::new())
+        });
+
+        scoped_thread_local!(static KEY: u32);
+
+        // Helpers which use a scope without going through `with` count as
+        // reading it.
+        thread::spawn(|| {
+            crate::check_unused_scopes(Some(|name| UNUSED.with(|u| u.borrow_mut().push(name))));
+            assert_eq!(KEY.with_or_install(&1, |v| *v), 1);
+            assert_eq!(KEY.with_or_init(|| 1, |v| *v), 1);
+            KEY.set_with_counter(&1, || KEY.next_local_id());
+            KEY.set_txn(&1, |_| {}, |_| {}, || KEY.commit());
+            KEY.set(&1, || KEY.defer(|| {}));
+            KEY.set(&1, || KEY.fuse());
+            KEY.set(&1, || KEY.zip_active(&KEY, |_, _| {}));
+            KEY.set(&1, || {});
+            assert_eq!(UNUSED.with(|u| u.borrow().clone()), ["KEY"]);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn set_restore_verification() {
+        scoped_thread_local!(static KEY: u32);
+
+        thread::spawn(|| {
+            KEY.set(&1, || KEY.set(&2, || {}));
+            let err = panic::catch_unwind(|| {
+                KEY.set(&1, || {
+                    // Leaves a scope active past the end of `set`.
+                    std::mem::forget(unsafe { KEY.set_guard(&2) });
+                });
+            })
+            .unwrap_err();
+            let msg = err.downcast_ref::<String>().unwrap();
+            assert!(msg.contains("`KEY` does not hold the value"), "{}", msg);
+            assert_eq!(KEY.get(), 2);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not hold the value `set` installed")]
+    fn set_guard_outliving_set() {
+        scoped_thread_local!(static KEY: u32);
+
+        let val = 2;
+        let mut guard = None;
+        KEY.set(&1, || guard = Some(unsafe { KEY.set_guard(&val) }));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without calling `set` first")]
+    fn with_unchecked_checks_in_debug() {
+        FOO.set(&1, || assert_eq!(unsafe { FOO.with_unchecked(|v| *v) }, 1));
+        unsafe { FOO.with_unchecked(|_| ()) };
+    }
+
+    #[test]
+    fn set_txn() {
+        scoped_thread_local!(static TXN: u32);
+
+        let log = RefCell::new(Vec::new());
+        let on_commit = |v: &u32| log.borrow_mut().push(("commit", *v));
+        let on_rollback = |v: &u32| log.borrow_mut().push(("rollback", *v));
+
+        let (r, committed) = TXN.set_txn(&1, on_commit, on_rollback, || {
+            // A nested transaction commits on its own.
+            TXN.set_txn(&2, on_commit, on_rollback, || {});
+            TXN.commit();
+            TXN.get()
+        });
+        assert_eq!((r, committed), (1, true));
+        assert_eq!(TXN.set_txn(&3, on_commit, on_rollback, || ()), ((), false));
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            TXN.set_txn(&4, on_commit, on_rollback, || {
+                TXN.commit();
+                panic!("boom");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            *log.borrow(),
+            [
+                ("rollback", 2),
+                ("commit", 1),
+                ("rollback", 3),
+                ("rollback", 4)
+            ]
+        );
+        assert!(panic::catch_unwind(|| TXN.set(&5, || TXN.commit())).is_err());
     }
 }
@@ -0,0 +1,158 @@
+//! Per-key usage counters, for exporting to a metrics backend.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ScopedKey;
+
+/// A sink for the counters of a key, see [`ScopedKey::export_to`].
+///
+/// Implement this to forward the counters to a metrics library.
+pub trait MetricRecorder {
+    /// Records the current `value` of the counter `name`, labeled with the
+    /// name of the key it counts for.
+    fn record_counter(&self, name: &'static str, key: &'static str, value: u64);
+}
+
+/// How often a key was used, summed over all threads.
+pub(crate) struct Counters {
+    sets: AtomicUsize,
+    reads: AtomicUsize,
+}
+
+impl Counters {
+    pub(crate) const fn new() -> Counters {
+        Counters {
+            sets: AtomicUsize::new(0),
+            reads: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn set(&self) {
+        self.sets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: ?Sized> ScopedKey<T> {
+    /// Emits the counters of this key to `recorder`.
+    ///
+    /// Two counters are recorded, both summed over all threads and labeled
+    /// with the name of the key: `scoped_tls.sets` counts the scopes which
+    /// have been entered, and `scoped_tls.reads` counts the calls to `with`
+    /// and `try_with`, including those made by other methods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use scoped_tls::MetricRecorder;
+    ///
+    /// struct Print;
+    ///
+    /// impl MetricRecorder for Print {
+    ///     fn record_counter(&self, name: &'static str, key: &'static str, value: u64) {
+    ///         println!("{}{{key=\"{}\"}} {}", name, key, value);
+    ///     }
+    /// }
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || FOO.with(|_| {}));
+    /// FOO.export_to(&Print);
+    /// # }
+    /// ```
+    pub fn export_to(&'static self, recorder: &impl MetricRecorder) {
+        let counters = &self.counters;
+        let sets = counters.sets.load(Ordering::Relaxed) as u64;
+        let reads = counters.reads.load(Ordering::Relaxed) as u64;
+        recorder.record_counter("scoped_tls.sets", self.name, sets);
+        recorder.record_counter("scoped_tls.reads", self.name, reads);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        sync::{Arc, Weak},
+    };
+
+    use crate::{MetricRecorder, ScopedKey};
+
+    #[derive(Default)]
+    struct Mock(RefCell<Vec<(&'static str, &'static str, u64)>>);
+
+    impl MetricRecorder for Mock {
+        fn record_counter(&self, name: &'static str, key: &'static str, value: u64) {
+            self.0.borrow_mut().push((name, key, value));
+        }
+    }
+
+    #[test]
+    fn export_to() {
+        scoped_thread_local!(static COUNTED: u32);
+
+        COUNTED.set(&1, || {
+            COUNTED.with(|_| {});
+            COUNTED.set(&2, || assert_eq!(COUNTED.get(), 2));
+            let _ = COUNTED.try_with(|_| {});
+        });
+        let mock = Mock::default();
+        COUNTED.export_to(&mock);
+        assert_eq!(
+            mock.0.into_inner(),
+            [
+                ("scoped_tls.sets", "COUNTED", 2),
+                ("scoped_tls.reads", "COUNTED", 3),
+            ]
+        );
+    }
+
+    fn reads<T: ?Sized>(key: &'static ScopedKey<T>) -> u64 {
+        let mock = Mock::default();
+        key.export_to(&mock);
+        mock.0.into_inner()[1].2
+    }
+
+    #[test]
+    fn helpers_count_reads() {
+        scoped_thread_local!(static SCALE: f64);
+        scoped_thread_local!(static BUF: [u8]);
+        scoped_thread_local!(static MAP: HashMap<u32, u32>);
+        scoped_thread_local!(static STATE: Weak<u32>);
+        scoped_thread_local!(static NAME: str);
+        scoped_thread_local!(static LIMIT: u32);
+
+        SCALE.get_or_nan();
+        SCALE.set(&0.5, || SCALE.get_or_nan());
+        assert_eq!(reads(&SCALE), 2);
+
+        BUF.set(&[1, 2][..], || BUF.as_ptr_range());
+        assert_eq!(reads(&BUF), 1);
+
+        MAP.set(&HashMap::new(), || {
+            MAP.get_key(&1);
+            MAP.contains(&1);
+            MAP.is(|map| map.is_empty());
+        });
+        assert_eq!(reads(&MAP), 3);
+
+        let state = Arc::new(1);
+        STATE.set(&Arc::downgrade(&state), || STATE.upgrade());
+        assert_eq!(reads(&STATE), 1);
+
+        NAME.as_str_or("none", |_| {});
+        NAME.set("name", || NAME.as_str_or("none", |_| {}));
+        assert_eq!(reads(&NAME), 2);
+
+        LIMIT.set(&1, || LIMIT.with_or_global_once(|| 2, |_| {}));
+        assert_eq!(reads(&LIMIT), 1);
+    }
+}
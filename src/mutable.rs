@@ -0,0 +1,280 @@
+//! Scoped thread-local storage handing out mutable references.
+
+use std::{any::Any, cell::Cell, marker, ptr::NonNull, thread::LocalKey};
+
+/// Type representing a thread local storage key corresponding to a mutable
+/// reference to the type parameter `T`.
+///
+/// Keys are declared with `scoped_thread_local!(static mut NAME: T)`. Unlike
+/// [`ScopedKey`](crate::ScopedKey), which only hands out shared references,
+/// `set` takes a `&mut T` and `with` lends it back out mutably. A borrow flag
+/// in each scope makes sure only one `&mut T` is handed out at a time:
+/// calling `with` again while an outer `with` of the same scope is still
+/// running will panic rather than create an aliasing mutable reference.
+///
+/// Like `ScopedKey`, the type `T` may be unsized, so a key can hold e.g. a
+/// `dyn Any` for type-erased context.
+pub struct ScopedKeyMut<T: ?Sized> {
+    inner: &'static LocalKey<Cell<*const ()>>,
+    _marker: marker::PhantomData<T>,
+}
+
+unsafe impl<T: ?Sized> Sync for ScopedKeyMut<T> {}
+
+/// The per-`set` storage the thread local points at.
+struct Slot<T: ?Sized> {
+    value: NonNull<T>,
+    borrowed: Cell<bool>,
+}
+
+impl<T: ?Sized> ScopedKeyMut<T> {
+    #[doc(hidden)]
+    /// # Safety
+    /// `inner` must only be accessed through `ScopedKeyMut`'s API
+    pub const unsafe fn new(inner: &'static LocalKey<Cell<*const ()>>) -> Self {
+        Self {
+            inner,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Inserts a mutable reference into this scoped thread local storage slot
+    /// for a duration of a closure.
+    ///
+    /// Upon return, this function will restore the previous value, if any
+    /// was available.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static mut FOO: u32);
+    ///
+    /// # fn main() {
+    /// let mut val = 1;
+    /// FOO.set(&mut val, || {
+    ///     FOO.with(|v| *v += 1);
+    /// });
+    /// assert_eq!(val, 2);
+    /// # }
+    /// ```
+    pub fn set<F, R>(&'static self, t: &mut T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct Reset {
+            key: &'static LocalKey<Cell<*const ()>>,
+            val: *const (),
+        }
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                // If the thread local is already gone there is nothing left
+                // which could observe the slot.
+                let _ = self.key.try_with(|c| c.set(self.val));
+            }
+        }
+        let slot = Slot {
+            value: NonNull::from(t),
+            borrowed: Cell::new(false),
+        };
+        let prev = self.inner.with(|c| {
+            let prev = c.get();
+            c.set(&slot as *const Slot<T> as *const ());
+            prev
+        });
+        let _reset = Reset {
+            key: self.inner,
+            val: prev,
+        };
+        f()
+    }
+
+    /// Gets a mutable reference to the value out of this scoped variable.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called, or
+    /// if the value is already being accessed by an enclosing call to `with`.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        struct Release<'a>(&'a Cell<bool>);
+        impl Drop for Release<'_> {
+            fn drop(&mut self) {
+                self.0.set(false);
+            }
+        }
+        let val = self.inner.with(|c| c.get());
+        assert!(
+            !val.is_null(),
+            "cannot access a scoped thread local variable without calling `set` first"
+        );
+        let slot = unsafe { &*(val as *const Slot<T>) };
+        assert!(
+            !slot.borrowed.replace(true),
+            "cannot access a scoped thread local variable that is already being accessed"
+        );
+        let _release = Release(&slot.borrowed);
+        unsafe { f(&mut *slot.value.as_ptr()) }
+    }
+
+    /// Test whether this TLS key has been `set` for the current thread.
+    pub fn is_set(&'static self) -> bool {
+        self.inner.with(|c| !c.get().is_null())
+    }
+}
+
+impl<U> ScopedKeyMut<Option<U>> {
+    /// Takes the value out of the scoped slot for the duration of `f`, then
+    /// stores the value `f` hands back.
+    ///
+    /// While `f` runs the slot holds `None`, so nested code can tell that the
+    /// value is in use, and `with` can be called again. If `f` panics the
+    /// slot is left empty.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`, or if
+    /// the slot is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static mut BUF: Option<Vec<u8>>);
+    ///
+    /// # fn main() {
+    /// let mut buf = Some(Vec::new());
+    /// BUF.set(&mut buf, || {
+    ///     let len = BUF.take_for(|mut buf| {
+    ///         buf.push(1);
+    ///         let len = buf.len();
+    ///         (buf, len)
+    ///     });
+    ///     assert_eq!(len, 1);
+    /// });
+    /// assert_eq!(buf, Some(vec![1]));
+    /// # }
+    /// ```
+    pub fn take_for<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(U) -> (U, R),
+    {
+        let value = self.with(|slot| slot.take()).expect(
+            "cannot take the value of a scoped thread local variable which is already empty",
+        );
+        let (value, r) = f(value);
+        self.with(|slot| *slot = Some(value));
+        r
+    }
+}
+
+impl ScopedKeyMut<dyn Any> {
+    /// Gets a mutable reference to the value out of this scoped variable,
+    /// downcast to the concrete type `U`.
+    ///
+    /// Returns `None` without calling `f` if the stored value is not a `U`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::any::Any;
+    ///
+    /// scoped_thread_local!(static mut CTX: dyn Any);
+    ///
+    /// # fn main() {
+    /// let mut count = 0u32;
+    /// CTX.set(&mut count, || {
+    ///     assert_eq!(CTX.with_downcast_mut(|c: &mut u32| *c += 1), Some(()));
+    ///     assert_eq!(CTX.with_downcast_mut(|_: &mut String| ()), None);
+    /// });
+    /// assert_eq!(count, 1);
+    /// # }
+    /// ```
+    pub fn with_downcast_mut<U: 'static, F, R>(&'static self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut U) -> R,
+    {
+        self.with(|v| v.downcast_mut::<U>().map(f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    #[test]
+    fn smoke() {
+        scoped_thread_local!(static mut FOO: Vec<u32>);
+
+        assert!(!FOO.is_set());
+        let mut v = Vec::new();
+        FOO.set(&mut v, || {
+            assert!(FOO.is_set());
+            FOO.with(|v| v.push(1));
+            FOO.with(|v| v.push(2));
+        });
+        assert!(!FOO.is_set());
+        assert_eq!(v, [1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "already being accessed")]
+    fn reentrant_with_panics() {
+        scoped_thread_local!(static mut FOO: u32);
+
+        FOO.set(&mut 1, || {
+            FOO.with(|_| FOO.with(|_| {}));
+        });
+    }
+
+    #[test]
+    fn downcast_mut() {
+        scoped_thread_local!(static mut CTX: dyn Any);
+
+        let mut names = vec![String::from("a")];
+        CTX.set(&mut names, || {
+            let len = CTX.with_downcast_mut(|names: &mut Vec<String>| {
+                names.push(String::from("b"));
+                names.len()
+            });
+            assert_eq!(len, Some(2));
+            assert_eq!(CTX.with_downcast_mut(|_: &mut u32| ()), None);
+        });
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn take_for() {
+        scoped_thread_local!(static mut STATE: Option<String>);
+
+        let mut state = Some(String::from("a"));
+        STATE.set(&mut state, || {
+            let seen = STATE.take_for(|mut s| {
+                assert!(STATE.with(|nested| nested.is_none()));
+                s.push('b');
+                (s, 2)
+            });
+            assert_eq!(seen, 2);
+            STATE.with(|s| assert_eq!(s.as_deref(), Some("ab")));
+        });
+        assert_eq!(state.as_deref(), Some("ab"));
+
+        let mut empty = None;
+        STATE.set(&mut empty, || {
+            assert!(std::panic::catch_unwind(|| STATE.take_for(|s| (s, ()))).is_err());
+        });
+    }
+}
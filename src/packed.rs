@@ -0,0 +1,323 @@
+//! Scoped thread-local storage of small `Copy` values, packed into a word.
+
+use std::{
+    cell::Cell,
+    marker,
+    num::{NonZeroU16, NonZeroU32, NonZeroU8, NonZeroUsize},
+    thread::LocalKey,
+};
+
+/// A `Copy` type whose values can be packed into a non-zero `usize`.
+///
+/// [`CopyScopedKey`] stores the packed value itself in its thread local, and
+/// uses zero to mean that no value is set. The conversion is safe code, so an
+/// implementation which doesn't round-trip only returns wrong values.
+///
+/// # Examples
+///
+/// ```
+/// use std::num::NonZeroUsize;
+///
+/// use scoped_tls::Packed;
+///
+/// #[derive(Clone, Copy, Debug, PartialEq)]
+/// enum Mode {
+///     Strict,
+///     Lenient,
+/// }
+///
+/// impl Packed for Mode {
+///     fn pack(self) -> NonZeroUsize {
+///         NonZeroUsize::new(self as usize + 1).unwrap()
+///     }
+///
+///     fn unpack(bits: NonZeroUsize) -> Mode {
+///         match bits.get() {
+///             1 => Mode::Strict,
+///             _ => Mode::Lenient,
+///         }
+///     }
+/// }
+///
+/// assert_eq!(Mode::unpack(Mode::Lenient.pack()), Mode::Lenient);
+/// ```
+pub trait Packed: Copy {
+    /// Packs `self` into a non-zero word.
+    fn pack(self) -> NonZeroUsize;
+
+    /// Recovers a value from what `pack` returned for it.
+    fn unpack(bits: NonZeroUsize) -> Self;
+}
+
+// Integers are offset by one to keep zero free. That only works for types
+// with fewer bits than `usize`.
+macro_rules! packed_ints {
+    ($($ty:ty as $unsigned:ty),*) => ($(
+        impl Packed for $ty {
+            #[inline]
+            fn pack(self) -> NonZeroUsize {
+                let bits = self as $unsigned as usize + 1;
+                // Never zero, as `$unsigned` has fewer bits than `usize`.
+                NonZeroUsize::new(bits).unwrap()
+            }
+
+            #[inline]
+            fn unpack(bits: NonZeroUsize) -> $ty {
+                (bits.get() - 1) as $unsigned as $ty
+            }
+        }
+    )*);
+}
+
+packed_ints!(u8 as u8, i8 as u8);
+#[cfg(not(target_pointer_width = "16"))]
+packed_ints!(u16 as u16, i16 as u16);
+#[cfg(target_pointer_width = "64")]
+packed_ints!(u32 as u32, i32 as u32);
+
+// Non-zero integers are stored as they are.
+macro_rules! packed_non_zero {
+    ($($ty:ident),*) => ($(
+        impl Packed for $ty {
+            #[inline]
+            fn pack(self) -> NonZeroUsize {
+                // Widening keeps the value non-zero.
+                NonZeroUsize::new(self.get() as usize).unwrap()
+            }
+
+            #[inline]
+            fn unpack(bits: NonZeroUsize) -> $ty {
+                // `pack` widened a non-zero value, so this can't truncate.
+                $ty::new(bits.get() as _).unwrap()
+            }
+        }
+    )*);
+}
+
+packed_non_zero!(NonZeroU8, NonZeroU16);
+#[cfg(not(target_pointer_width = "16"))]
+packed_non_zero!(NonZeroU32);
+
+impl Packed for NonZeroUsize {
+    #[inline]
+    fn pack(self) -> NonZeroUsize {
+        self
+    }
+
+    #[inline]
+    fn unpack(bits: NonZeroUsize) -> NonZeroUsize {
+        bits
+    }
+}
+
+impl Packed for bool {
+    #[inline]
+    fn pack(self) -> NonZeroUsize {
+        u8::from(self).pack()
+    }
+
+    #[inline]
+    fn unpack(bits: NonZeroUsize) -> bool {
+        u8::unpack(bits) != 0
+    }
+}
+
+impl Packed for char {
+    #[inline]
+    fn pack(self) -> NonZeroUsize {
+        // A `char` is at most 0x10FFFF, so this never reaches zero.
+        NonZeroUsize::new(self as usize + 1).unwrap()
+    }
+
+    #[inline]
+    fn unpack(bits: NonZeroUsize) -> char {
+        char::from_u32((bits.get() - 1) as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+}
+
+/// Type representing a thread local storage key holding a small `Copy`
+/// value, rather than a reference to one.
+///
+/// Keys are declared with `scoped_thread_local!(static copy NAME: T)`, where
+/// `T` implements [`Packed`]. The thread local holds the packed value
+/// directly, so reading it is a single load without following a pointer or
+/// checking a separate discriminant, which suits flags consulted in hot
+/// loops like parsers.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static copy DEPTH: u8);
+///
+/// # fn main() {
+/// assert_eq!(DEPTH.try_get(), None);
+/// DEPTH.set(1, || {
+///     assert_eq!(DEPTH.get(), 1);
+///     DEPTH.set(2, || assert_eq!(DEPTH.get(), 2));
+///     assert_eq!(DEPTH.get(), 1);
+/// });
+/// assert!(!DEPTH.is_set());
+/// # }
+/// ```
+pub struct CopyScopedKey<T: Packed> {
+    inner: &'static LocalKey<Cell<usize>>,
+    _marker: marker::PhantomData<T>,
+}
+
+unsafe impl<T: Packed> Sync for CopyScopedKey<T> {}
+
+impl<T: Packed> CopyScopedKey<T> {
+    #[doc(hidden)]
+    /// # Safety
+    /// `inner` must only be accessed through `CopyScopedKey`'s API
+    pub const unsafe fn new(inner: &'static LocalKey<Cell<usize>>) -> Self {
+        Self {
+            inner,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Stores `value` in this scoped thread local storage slot for the
+    /// duration of a closure.
+    ///
+    /// Upon return, this function will restore the previous value, if any
+    /// was available.
+    pub fn set<F, R>(&'static self, value: T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct Reset {
+            key: &'static LocalKey<Cell<usize>>,
+            val: usize,
+        }
+        impl Drop for Reset {
+            // `Reset` isn't generic, so it's only inlined into callers in
+            // other crates with this.
+            #[inline]
+            fn drop(&mut self) {
+                // If the thread local is already gone there is nothing left
+                // which could observe the value.
+                let _ = self.key.try_with(|c| c.set(self.val));
+            }
+        }
+        let prev = self.inner.with(|c| c.replace(value.pack().get()));
+        let _reset = Reset {
+            key: self.inner,
+            val: prev,
+        };
+        f()
+    }
+
+    /// Returns the current value.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    pub fn get(&'static self) -> T {
+        self.try_get()
+            .expect("cannot access a scoped thread local variable without calling `set` first")
+    }
+
+    /// Returns the current value, or `None` if `set` has not previously been
+    /// called.
+    pub fn try_get(&'static self) -> Option<T> {
+        let bits = self.inner.try_with(Cell::get).unwrap_or(0);
+        NonZeroUsize::new(bits).map(T::unpack)
+    }
+
+    /// Test whether this TLS key has been `set` for the current thread.
+    pub fn is_set(&'static self) -> bool {
+        self.try_get().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Debug,
+        num::{NonZeroU16, NonZeroU32, NonZeroU8, NonZeroUsize},
+        panic,
+    };
+
+    use crate::Packed;
+
+    fn round_trip<T: Packed + PartialEq + Debug>(values: &[T]) {
+        for &v in values {
+            assert_eq!(T::unpack(v.pack()), v);
+        }
+    }
+
+    #[test]
+    fn packing() {
+        round_trip(&[0, 1, u8::MAX]);
+        round_trip(&[i8::MIN, -1, 0, i8::MAX]);
+        round_trip(&[0, u16::MAX]);
+        round_trip(&[i16::MIN, -1, 0, i16::MAX]);
+        #[cfg(target_pointer_width = "64")]
+        {
+            round_trip(&[0, u32::MAX]);
+            round_trip(&[i32::MIN, -1, 0, i32::MAX]);
+        }
+        round_trip(&[NonZeroU8::new(1).unwrap(), NonZeroU8::new(u8::MAX).unwrap()]);
+        round_trip(&[
+            NonZeroU16::new(1).unwrap(),
+            NonZeroU16::new(u16::MAX).unwrap(),
+        ]);
+        round_trip(&[
+            NonZeroU32::new(1).unwrap(),
+            NonZeroU32::new(u32::MAX).unwrap(),
+        ]);
+        round_trip(&[
+            NonZeroUsize::new(1).unwrap(),
+            NonZeroUsize::new(usize::MAX).unwrap(),
+        ]);
+        round_trip(&[false, true]);
+        round_trip(&['\0', 'a', char::MAX]);
+    }
+
+    #[test]
+    fn smoke() {
+        scoped_thread_local!(static copy FLAG: bool);
+
+        assert!(!FLAG.is_set());
+        assert_eq!(FLAG.try_get(), None);
+        FLAG.set(false, || {
+            assert!(FLAG.is_set());
+            assert!(!FLAG.get());
+            FLAG.set(true, || assert!(FLAG.get()));
+            assert!(!FLAG.get());
+        });
+        assert!(!FLAG.is_set());
+    }
+
+    #[test]
+    fn zero_is_a_value() {
+        scoped_thread_local!(static copy OFFSET: i8);
+
+        OFFSET.set(0, || assert_eq!(OFFSET.try_get(), Some(0)));
+        OFFSET.set(-1, || assert_eq!(OFFSET.get(), -1));
+        assert_eq!(OFFSET.try_get(), None);
+    }
+
+    #[test]
+    fn restores_after_panic() {
+        scoped_thread_local!(static copy LEVEL: u16);
+
+        LEVEL.set(1, || {
+            let _ = panic::catch_unwind(|| LEVEL.set(2, || panic!()));
+            assert_eq!(LEVEL.get(), 1);
+        });
+        assert!(!LEVEL.is_set());
+    }
+
+    #[test]
+    #[should_panic(expected = "without calling `set` first")]
+    fn get_unset_panics() {
+        scoped_thread_local!(static copy LEVEL: u8);
+
+        LEVEL.get();
+    }
+}
@@ -0,0 +1,207 @@
+//! Propagating the value of a key to jobs run on a thread pool.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc,
+};
+
+use crate::{ScopedKey, StackInstallGuard};
+
+/// A thread pool, or anything else which can run jobs on other threads.
+///
+/// This is the interface [`ScopedKey::run_in_pool`] needs from a pool.
+pub trait Executor {
+    /// Runs `job` at some point, on some thread.
+    fn execute(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+impl<T: Clone + Send> ScopedKey<T> {
+    /// Runs `f` on `pool` with this key set to a clone of its current value,
+    /// and waits for the result.
+    ///
+    /// If `f` panics, the panic is resumed on the calling thread.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`, or if
+    /// the pool drops the job without running it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use scoped_tls::Executor;
+    ///
+    /// struct SpawnPerJob;
+    ///
+    /// impl Executor for SpawnPerJob {
+    ///     fn execute(&self, job: Box<dyn FnOnce() + Send>) {
+    ///         std::thread::spawn(job);
+    ///     }
+    /// }
+    ///
+    /// scoped_thread_local!(static REQUEST_ID: u64);
+    ///
+    /// # fn main() {
+    /// REQUEST_ID.set(&7, || {
+    ///     let id = REQUEST_ID.run_in_pool(&SpawnPerJob, || REQUEST_ID.get());
+    ///     assert_eq!(id, 7);
+    /// });
+    /// # }
+    /// ```
+    pub fn run_in_pool<P, F, R>(&'static self, pool: &P, f: F) -> R
+    where
+        P: Executor + ?Sized,
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let value = self.with(T::clone);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| self.set(&value, f)));
+            let _ = tx.send(result);
+        }));
+        match rx.recv() {
+            Ok(Ok(r)) => r,
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            Err(_) => panic!(
+                "the pool dropped the job for scoped thread local variable `{}`",
+                self.display_name()
+            ),
+        }
+    }
+
+    /// Returns clones of the values of all visible scopes, outermost first.
+    ///
+    /// Together with [`install_stack`](ScopedKey::install_stack) this moves
+    /// the whole nesting of scopes to another thread, rather than just the
+    /// innermost value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static SPAN: &'static str);
+    ///
+    /// # fn main() {
+    /// let stack = SPAN.set(&"request", || SPAN.set(&"parse", || SPAN.clone_stack()));
+    /// std::thread::spawn(move || {
+    ///     let _guard = SPAN.install_stack(stack);
+    ///     assert_eq!(SPAN.get(), "parse");
+    ///     assert_eq!(SPAN.with_depth(|_, depth| depth), 2);
+    /// })
+    /// .join()
+    /// .unwrap();
+    /// # }
+    /// ```
+    pub fn clone_stack(&'static self) -> Vec<T> {
+        self.stack()
+            .into_iter()
+            // Safety: `stack` only returns pointers to live values
+            .map(|v| unsafe { v.as_ref() }.clone())
+            .collect()
+    }
+
+    /// Installs `values` as nested scopes of this key, outermost first, until
+    /// the returned guard is dropped.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `set`. Scopes
+    /// installed before the panic are removed again.
+    pub fn install_stack(&'static self, values: Vec<T>) -> StackInstallGuard<T> {
+        StackInstallGuard::new(self, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic,
+        sync::{
+            mpsc::{channel, Sender},
+            Mutex,
+        },
+        thread,
+    };
+
+    use crate::Executor;
+
+    type Job = Box<dyn FnOnce() + Send>;
+
+    /// A pool with a single worker thread.
+    struct Worker(Mutex<Sender<Job>>);
+
+    impl Worker {
+        fn new() -> Worker {
+            let (tx, rx) = channel::<Job>();
+            thread::spawn(move || {
+                for job in rx {
+                    job();
+                }
+            });
+            Worker(Mutex::new(tx))
+        }
+    }
+
+    impl Executor for Worker {
+        fn execute(&self, job: Job) {
+            self.0.lock().unwrap().send(job).unwrap();
+        }
+    }
+
+    #[test]
+    fn run_in_pool() {
+        scoped_thread_local!(static NAME: String);
+
+        let pool = Worker::new();
+        let seen = NAME.set(&String::from("request"), || {
+            NAME.run_in_pool(&pool, || (NAME.with(|n| n.clone()), thread::current().id()))
+        });
+        assert_eq!(seen.0, "request");
+        assert_ne!(seen.1, thread::current().id());
+        assert!(!NAME.is_set());
+
+        let err = panic::catch_unwind(|| {
+            NAME.set(&String::new(), || {
+                NAME.run_in_pool(&pool, || panic!("boom"))
+            })
+        })
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<&str>(), Some(&"boom"));
+        // The worker survives panicking jobs.
+        assert_eq!(
+            NAME.set(&String::from("again"), || NAME
+                .run_in_pool(&pool, || NAME.with(|n| n.len()))),
+            5
+        );
+    }
+
+    #[test]
+    fn clone_stack() {
+        scoped_thread_local!(static DEPTH: String);
+
+        let stack = DEPTH.set(&String::from("a"), || {
+            DEPTH.set(&String::from("b"), || {
+                DEPTH.set(&String::from("c"), || DEPTH.clone_stack())
+            })
+        });
+        assert_eq!(stack, ["a", "b", "c"]);
+        thread::spawn(move || {
+            let guard = DEPTH.install_stack(stack);
+            assert_eq!(
+                DEPTH.with_depth(|v, depth| (v.clone(), depth)),
+                ("c".into(), 3)
+            );
+            assert_eq!(DEPTH.clone_stack(), ["a", "b", "c"]);
+            drop(guard);
+            assert!(!DEPTH.is_set());
+        })
+        .join()
+        .unwrap();
+    }
+}
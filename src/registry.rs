@@ -0,0 +1,519 @@
+//! A per-thread registry of the keys which have been `set` on that thread,
+//! and process-wide hooks observing scopes being entered and exited.
+//!
+//! Keys register themselves the first time they are `set` on a thread and
+//! are never removed, so the registry only ever holds as many entries as
+//! there are distinct keys in the program.
+
+use std::{
+    cell::RefCell,
+    fmt, ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        PoisonError, RwLock,
+    },
+};
+
+use crate::{lazy::Lazy, ScopedKey};
+
+type Hook = fn(&'static str);
+
+#[derive(Default)]
+struct Hooks {
+    enter: Vec<Hook>,
+    exit: Vec<Hook>,
+}
+
+/// Whether any hooks are installed, checked before touching `HOOKS`.
+static HAS_HOOKS: AtomicBool = AtomicBool::new(false);
+static HOOKS: Lazy<RwLock<Hooks>> = Lazy::new();
+
+fn hooks() -> &'static RwLock<Hooks> {
+    HOOKS.get(Default::default)
+}
+
+/// Registers `callback` to be called with the name of a key whenever a scope
+/// of that key is entered, on any thread.
+///
+/// Callbacks run after the new value has been installed. They can't be
+/// unregistered. When no callbacks are registered, entering a scope only
+/// costs a relaxed atomic load.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static FOO: u32);
+///
+/// # fn main() {
+/// scoped_tls::on_scope_enter(|name| println!("entered {}", name));
+/// scoped_tls::on_scope_exit(|name| println!("exited {}", name));
+/// FOO.set(&1, || {});
+/// # }
+/// ```
+pub fn on_scope_enter(callback: fn(&'static str)) {
+    add_hook(|hooks| hooks.enter.push(callback));
+}
+
+/// Registers `callback` to be called with the name of a key whenever a scope
+/// of that key is exited, on any thread.
+///
+/// Callbacks run after the previous value has been restored, including when
+/// the scope is exited by a panic. See [`on_scope_enter`].
+pub fn on_scope_exit(callback: fn(&'static str)) {
+    add_hook(|hooks| hooks.exit.push(callback));
+}
+
+fn add_hook(add: impl FnOnce(&mut Hooks)) {
+    add(&mut hooks().write().unwrap_or_else(PoisonError::into_inner));
+    HAS_HOOKS.store(true, Ordering::Release);
+}
+
+/// Removes all hooks, so tests installing them don't affect later tests.
+#[cfg(test)]
+pub(crate) fn clear_hooks() {
+    let mut hooks = hooks().write().unwrap_or_else(PoisonError::into_inner);
+    *hooks = Hooks::default();
+    HAS_HOOKS.store(false, Ordering::Release);
+}
+
+/// Returns whether any hooks are installed, so callers can skip resolving the
+/// name of a key when there are none.
+pub(crate) fn has_hooks() -> bool {
+    HAS_HOOKS.load(Ordering::Relaxed)
+}
+
+fn run_hooks(name: &'static str, which: fn(&Hooks) -> &[Hook]) {
+    if !has_hooks() {
+        return;
+    }
+    // Callbacks may register more callbacks, so don't hold the lock.
+    let callbacks = which(&hooks().read().unwrap_or_else(PoisonError::into_inner)).to_vec();
+    for callback in callbacks {
+        callback(name);
+    }
+}
+
+pub(crate) fn entered(name: &'static str) {
+    run_hooks(name, |hooks| &hooks.enter);
+}
+
+pub(crate) fn exited(name: &'static str) {
+    run_hooks(name, |hooks| &hooks.exit);
+}
+
+/// Type-erased view of a registered `ScopedKey`.
+pub(crate) trait Registered {
+    fn name(&'static self) -> &'static str;
+
+    fn is_set(&self) -> bool;
+
+    /// Formats the innermost value, or `..` if it was not `set_debug`.
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+
+    /// Formats the values of the visible scopes like `fmt_value`, outermost
+    /// first.
+    fn fmt_scopes(&self) -> Vec<String>;
+}
+
+thread_local!(static KEYS: RefCell<Vec<&'static dyn Registered>> = const {
+    RefCell::new(Vec::new())
+});
+
+impl<T: ?Sized> ScopedKey<T> {
+    /// Like `top`, but treats storage which is already destroyed as unset, so
+    /// the registry can still be inspected from thread local destructors.
+    fn top_or_null(&self) -> *const crate::Frame<T> {
+        self.inner
+            .try_with(|c| c.get() as *const crate::Frame<T>)
+            .unwrap_or(ptr::null())
+    }
+}
+
+impl<T: ?Sized> Registered for ScopedKey<T> {
+    fn name(&'static self) -> &'static str {
+        self.display_name()
+    }
+
+    fn is_set(&self) -> bool {
+        // Safety: frames in the list are alive until they unlink themselves
+        unsafe { self.top_or_null().as_ref() }.map_or(false, |frame| frame.value.is_some())
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let top = self.top_or_null();
+        // Safety: frames in the list are alive until they unlink themselves
+        match unsafe { top.as_ref() } {
+            Some(frame) => fmt_frame(frame, f),
+            None => f.write_str(".."),
+        }
+    }
+
+    fn fmt_scopes(&self) -> Vec<String> {
+        struct Scope<'a, T: ?Sized>(&'a crate::Frame<T>);
+        impl<T: ?Sized> fmt::Display for Scope<'_, T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt_frame(self.0, f)
+            }
+        }
+
+        let mut scopes = Vec::new();
+        let mut cur = self.top_or_null();
+        // Safety: frames in the list are alive until they unlink themselves
+        while let Some(frame) = unsafe { cur.as_ref() } {
+            if frame.value.is_none() {
+                break;
+            }
+            scopes.push(Scope(frame).to_string());
+            cur = frame.prev.get();
+        }
+        scopes.reverse();
+        scopes
+    }
+}
+
+fn fmt_frame<T: ?Sized>(frame: &crate::Frame<T>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *frame {
+        crate::Frame {
+            value: Some(value),
+            debug: Some(debug),
+            ..
+        } => debug(unsafe { value.as_ref() }, f),
+        _ => f.write_str(".."),
+    }
+}
+
+pub(crate) fn register<T: ?Sized>(key: &'static ScopedKey<T>) {
+    let key = key as &'static dyn Registered;
+    // Keys set while the list itself is torn down just aren't registered.
+    let _ = KEYS.try_with(|keys| {
+        let mut keys = keys.borrow_mut();
+        let addr = key as *const dyn Registered as *const ();
+        if !keys
+            .iter()
+            .any(|k| ptr::eq(*k as *const dyn Registered as *const (), addr))
+        {
+            keys.push(key);
+        }
+    });
+}
+
+static MAX_ACTIVE_KEYS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Limits how many distinct keys may be set at the same time on each thread.
+///
+/// Once `n` keys are set on a thread, entering a scope of any other key on
+/// that thread panics. Nested scopes of keys which are already set don't
+/// count against the limit, and neither do keys only holding their default.
+/// There is no limit by default.
+///
+/// The limit is process-wide and applies to all threads, including ones
+/// started later, but each thread's keys are counted separately.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static A: u32);
+/// scoped_thread_local!(static B: u32);
+///
+/// # fn main() {
+/// scoped_tls::set_max_active_keys(1);
+/// A.set(&1, || {
+///     A.set(&2, || {});
+///     assert!(std::panic::catch_unwind(|| B.set(&1, || {})).is_err());
+/// });
+/// # }
+/// ```
+pub fn set_max_active_keys(n: usize) {
+    MAX_ACTIVE_KEYS.store(n, Ordering::Relaxed);
+}
+
+/// Panics if setting `key` would exceed the limit of `set_max_active_keys`.
+pub(crate) fn check_active_keys<T: ?Sized>(key: &'static ScopedKey<T>) {
+    let max = MAX_ACTIVE_KEYS.load(Ordering::Relaxed);
+    if max == usize::MAX || Registered::is_set(key) {
+        return;
+    }
+    let active = KEYS
+        .try_with(|keys| keys.borrow().iter().filter(|k| k.is_set()).count())
+        .unwrap_or(0);
+    if active >= max {
+        panic!(
+            "cannot set scoped thread local variable `{}`: {} keys are already set on this thread, \
+             which is the limit",
+            key.display_name(),
+            active
+        );
+    }
+}
+
+/// Writes the name and value of each key currently set on this thread, one
+/// per line.
+///
+/// Only values installed with [`ScopedKey::set_debug`] can be formatted, the
+/// values of keys installed with `set` are written as `..`.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static FOO: u32);
+///
+/// # fn main() {
+/// FOO.set_debug(&1, || {
+///     let mut dump = String::new();
+///     scoped_tls::dump_active(&mut dump).unwrap();
+///     assert_eq!(dump, "FOO = 1\n");
+/// });
+/// # }
+/// ```
+pub fn dump_active(w: &mut impl fmt::Write) -> fmt::Result {
+    struct Value(&'static dyn Registered);
+    impl fmt::Display for Value {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt_value(f)
+        }
+    }
+
+    // Formatting a value may itself `set` a key, so don't hold the borrow.
+    let keys = KEYS
+        .try_with(|keys| keys.borrow().clone())
+        .unwrap_or_default();
+    for key in keys {
+        if key.is_set() {
+            writeln!(w, "{} = {}", key.name(), Value(key))?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders the keys currently set on this thread as a tree, with each
+/// nested scope indented one level below the scope it shadows.
+///
+/// Values are formatted as in [`dump_active`].
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// scoped_thread_local!(static FOO: u32);
+///
+/// # fn main() {
+/// FOO.set_debug(&1, || {
+///     FOO.set(&2, || {
+///         assert_eq!(scoped_tls::export_scope_tree(), "FOO\n  1\n    ..\n");
+///     });
+/// });
+/// # }
+/// ```
+pub fn export_scope_tree() -> String {
+    // Formatting a value may itself `set` a key, so don't hold the borrow.
+    let keys = KEYS
+        .try_with(|keys| keys.borrow().clone())
+        .unwrap_or_default();
+    let mut tree = String::new();
+    for key in keys {
+        let scopes = key.fmt_scopes();
+        if scopes.is_empty() {
+            continue;
+        }
+        tree.push_str(key.name());
+        tree.push('\n');
+        for (depth, scope) in scopes.iter().enumerate() {
+            tree.push_str(&format!(
+                "{:indent$}{}\n",
+                "",
+                scope,
+                indent = 2 * (depth + 1)
+            ));
+        }
+    }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, panic, sync::mpsc, thread};
+
+    #[test]
+    fn dump() {
+        scoped_thread_local!(static NAME: &'static str);
+        scoped_thread_local!(static DEPTH: u32);
+        scoped_thread_local!(static OPAQUE: u32);
+
+        thread::spawn(|| {
+            let dump = || {
+                let mut s = String::new();
+                crate::dump_active(&mut s).unwrap();
+                s
+            };
+            assert_eq!(dump(), "");
+            NAME.set_debug(&"outer", || {
+                DEPTH.set_debug(&2, || {
+                    OPAQUE.set(&3, || {
+                        let s = dump();
+                        assert!(s.contains("NAME = \"outer\"\n"), "{}", s);
+                        assert!(s.contains("DEPTH = 2\n"), "{}", s);
+                        assert!(s.contains("OPAQUE = ..\n"), "{}", s);
+                    });
+                });
+                assert_eq!(dump(), "NAME = \"outer\"\n");
+            });
+            assert_eq!(dump(), "");
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn dump_from_destructor() {
+        scoped_thread_local!(static NAME: &'static str);
+
+        // Whichever of the key list and `LOCAL` is dropped first, inspecting
+        // the registry from a destructor must not panic.
+        struct Dump(mpsc::Sender<String>);
+        impl Drop for Dump {
+            fn drop(&mut self) {
+                let mut dump = String::new();
+                NAME.set_debug(&"dtor", || crate::dump_active(&mut dump).unwrap());
+                dump.push_str(&crate::export_scope_tree());
+                self.0.send(dump).unwrap();
+            }
+        }
+        thread_local!(static LOCAL: RefCell<Option<Dump>> = const { RefCell::new(None) });
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            LOCAL.with(|l| *l.borrow_mut() = Some(Dump(tx)));
+            // Usually drops the key list before `LOCAL`.
+            NAME.set_debug(&"thread", || {});
+        })
+        .join()
+        .unwrap();
+        let dump = rx.recv().unwrap();
+        assert!(dump.is_empty() || dump == "NAME = \"dtor\"\n", "{}", dump);
+    }
+
+    #[test]
+    fn hooks() {
+        thread_local!(static EVENTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) });
+
+        scoped_thread_local!(static OUTER: u32);
+        scoped_thread_local!(static INNER: u32);
+
+        fn enter(name: &'static str) {
+            EVENTS.with(|e| e.borrow_mut().push(format!("enter {}", name)));
+        }
+        fn exit(name: &'static str) {
+            EVENTS.with(|e| e.borrow_mut().push(format!("exit {}", name)));
+        }
+        struct Clear;
+        impl Drop for Clear {
+            fn drop(&mut self) {
+                super::clear_hooks();
+            }
+        }
+
+        let _lock = crate::tests::lock_globals();
+        let _clear = Clear;
+        // Hooks are process-wide, so only look at events from this thread.
+        crate::on_scope_enter(enter);
+        crate::on_scope_exit(exit);
+
+        thread::spawn(|| {
+            OUTER.set(&1, || {
+                INNER.set(&2, || {});
+                let _suspended = OUTER.suspend();
+            });
+            let events = EVENTS.with(|e| e.borrow().clone());
+            assert_eq!(
+                events,
+                ["enter OUTER", "enter INNER", "exit INNER", "exit OUTER"]
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn max_active_keys() {
+        scoped_thread_local!(static A: u32);
+        scoped_thread_local!(static B: u32);
+        scoped_thread_local!(static C: u32);
+        scoped_thread_local!(static D: u32);
+        scoped_thread_local!(static E: u32);
+        scoped_thread_local!(static F: u32);
+        scoped_thread_local!(static G: u32);
+        scoped_thread_local!(static H: u32);
+        scoped_thread_local!(static I: u32);
+
+        struct Restore;
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                crate::set_max_active_keys(usize::MAX);
+            }
+        }
+
+        fn nest(keys: &[&'static crate::ScopedKey<u32>], f: &dyn Fn()) {
+            match keys.split_first() {
+                Some((key, rest)) => key.set(&1, || nest(rest, f)),
+                None => f(),
+            }
+        }
+
+        // The limit is high enough that other tests running meanwhile don't
+        // reach it.
+        let _lock = crate::tests::lock_globals();
+        let _restore = Restore;
+        crate::set_max_active_keys(8);
+        thread::spawn(|| {
+            nest(&[&A, &B, &C, &D, &E, &F, &G, &H], &|| {
+                A.set(&2, || {});
+                let err = panic::catch_unwind(|| I.set(&1, || {})).unwrap_err();
+                let msg = err.downcast_ref::<String>().unwrap();
+                assert!(msg.contains("`I`"), "{}", msg);
+                // Keys set on other threads don't count.
+                thread::spawn(|| I.set(&1, || {})).join().unwrap();
+            });
+            nest(&[&B, &C, &D, &E, &F, &G, &H, &I], &|| {});
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn scope_tree() {
+        scoped_thread_local!(static OUTER: &'static str);
+        scoped_thread_local!(static INNER: u32);
+
+        thread::spawn(|| {
+            assert_eq!(crate::export_scope_tree(), "");
+            OUTER.set_debug(&"a", || {
+                INNER.set(&1, || {
+                    OUTER.set_debug(&"b", || {
+                        INNER.set_debug(&2, || {
+                            OUTER.set(&"c", || {
+                                assert_eq!(
+                                    crate::export_scope_tree(),
+                                    "OUTER\n  \"a\"\n    \"b\"\n      ..\n\
+                                     INNER\n  ..\n    2\n"
+                                );
+                            });
+                        });
+                    });
+                });
+            });
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! Timing a sample of the scopes of each key.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::ScopedKey;
+
+static SAMPLE_RATE: AtomicUsize = AtomicUsize::new(1);
+
+thread_local!(static SCOPES: Cell<usize> = const { Cell::new(0) });
+
+/// Sets how many scopes entered with `set` on each thread make up one
+/// sample, for all keys.
+///
+/// With a rate of `n`, only every `n`th scope on a thread is timed, counting
+/// the scopes of all keys. A rate of 1, the default, times every scope, and 0
+/// turns sampling off.
+pub fn set_sample_rate(n: usize) {
+    SAMPLE_RATE.store(n, Ordering::Relaxed);
+}
+
+/// Returns the start time of the scope being entered on this thread, if it
+/// should be timed.
+pub(crate) fn sample() -> Option<Instant> {
+    let rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    let n = SCOPES
+        .try_with(|n| {
+            let cur = n.get();
+            n.set(cur.wrapping_add(1));
+            cur
+        })
+        .ok()?;
+    if rate != 0 && n % rate == 0 {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+/// The timings sampled for a key, summed over all threads.
+pub(crate) struct Samples {
+    count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl Samples {
+    pub(crate) const fn new() -> Samples {
+        Samples {
+            count: AtomicU64::new(0),
+            nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, start: Instant) {
+        let nanos = start.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: ?Sized> ScopedKey<T> {
+    /// Returns how long the sampled scopes of this key took on average, or
+    /// `None` if none have been sampled yet.
+    ///
+    /// Only scopes entered with `set` and similar closure-based methods are
+    /// sampled, see [`set_sample_rate`]. Scopes which panicked aren't
+    /// included.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// scoped_tls::set_sample_rate(10);
+    /// for i in 0..100 {
+    ///     FOO.set(&i, || {});
+    /// }
+    /// println!("FOO scopes take {:?}", FOO.average_scope_duration().unwrap());
+    /// # }
+    /// ```
+    pub fn average_scope_duration(&'static self) -> Option<Duration> {
+        let count = self.samples.count.load(Ordering::Relaxed);
+        let nanos = self.samples.nanos.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos(nanos / count))
+    }
+
+    /// Returns how many scopes of this key have been sampled.
+    pub fn sampled_scopes(&'static self) -> u64 {
+        self.samples.count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn sample_rate() {
+        struct Restore;
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                crate::set_sample_rate(1);
+            }
+        }
+        scoped_thread_local!(static SAMPLED: u32);
+
+        let _lock = crate::tests::lock_globals();
+        let _restore = Restore;
+        thread::spawn(|| {
+            crate::set_sample_rate(3);
+            assert_eq!(SAMPLED.average_scope_duration(), None);
+            for i in 0..7 {
+                // Only iterations 0, 3 and 6 are sampled, so the slow ones
+                // don't count.
+                let sleep = if i % 3 == 0 { 1 } else { 50 };
+                SAMPLED.set(&i, || thread::sleep(Duration::from_millis(sleep)));
+            }
+            assert_eq!(SAMPLED.sampled_scopes(), 3);
+            let average = SAMPLED.average_scope_duration().unwrap();
+            assert!(average >= Duration::from_millis(1), "{:?}", average);
+            assert!(average < Duration::from_millis(50), "{:?}", average);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+//! Keeping a key set while a stream produces its items.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::ScopedKey;
+
+/// A stream of values produced asynchronously.
+///
+/// This is the interface [`ScopedKey::set_stream`] needs from a stream. It
+/// has the same shape as the `Stream` trait of the `futures` crate, so an
+/// adapter forwarding `poll_next` is all it takes to wrap one of those.
+pub trait Stream {
+    /// The type of the values the stream yields.
+    type Item;
+
+    /// Attempts to pull the next value out of the stream, returning
+    /// `Poll::Ready(None)` once it is exhausted.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+}
+
+/// A stream which has a key set while it is polled, returned by
+/// [`ScopedKey::set_stream`].
+pub struct ScopedStream<'a, T: ?Sized + 'static, S> {
+    key: &'static ScopedKey<T>,
+    value: &'a T,
+    stream: S,
+}
+
+impl<T: ?Sized, S: Stream> Stream for ScopedStream<'_, T, S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        // Safety: `stream` is pinned along with `self`, and is never moved
+        // out of it
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        this.key.set(this.value, || stream.poll_next(cx))
+    }
+}
+
+impl<T: ?Sized> ScopedKey<T> {
+    /// Wraps `stream` so that this key is set to `t` whenever it is polled.
+    ///
+    /// The value is installed around each call to `poll_next` and removed
+    /// before it returns, so whatever produces the items sees it, but other
+    /// tasks polled on the same thread in between don't.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::{
+    ///     pin::Pin,
+    ///     task::{Context, Poll},
+    /// };
+    ///
+    /// use scoped_tls::Stream;
+    ///
+    /// scoped_thread_local!(static REQUEST: u32);
+    ///
+    /// /// Yields the current request id, forever.
+    /// struct RequestIds;
+    ///
+    /// impl Stream for RequestIds {
+    ///     type Item = u32;
+    ///
+    ///     fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<u32>> {
+    ///         Poll::Ready(Some(REQUEST.get()))
+    ///     }
+    /// }
+    ///
+    /// # fn main() {
+    /// # struct Noop;
+    /// # impl std::task::Wake for Noop {
+    /// #     fn wake(self: std::sync::Arc<Self>) {}
+    /// # }
+    /// # let waker = std::task::Waker::from(std::sync::Arc::new(Noop));
+    /// # let mut cx = Context::from_waker(&waker);
+    /// let mut ids = REQUEST.set_stream(&7, RequestIds);
+    /// assert_eq!(Pin::new(&mut ids).poll_next(&mut cx), Poll::Ready(Some(7)));
+    /// assert!(!REQUEST.is_set());
+    /// # }
+    /// ```
+    pub fn set_stream<'a, S: Stream>(&'static self, t: &'a T, stream: S) -> ScopedStream<'a, T, S> {
+        ScopedStream {
+            key: self,
+            value: t,
+            stream,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use crate::Stream;
+
+    scoped_thread_local!(static FOO: u32);
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Yields the value of `FOO` for each item, after one pending poll.
+    struct Items {
+        left: u32,
+        pending: bool,
+    }
+
+    impl Stream for Items {
+        type Item = u32;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u32>> {
+            if self.left == 0 {
+                return Poll::Ready(None);
+            }
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.left -= 1;
+            Poll::Ready(Some(FOO.get() + self.left))
+        }
+    }
+
+    #[test]
+    fn set_stream() {
+        let waker = Waker::from(Arc::new(Noop));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut items = FOO.set_stream(
+            &10,
+            Items {
+                left: 2,
+                pending: false,
+            },
+        );
+        let mut items = Pin::new(&mut items);
+        let mut polls = Vec::new();
+        FOO.set(&20, || loop {
+            let poll = items.as_mut().poll_next(&mut cx);
+            // The wrapped value is gone again between polls.
+            assert_eq!(FOO.get(), 20);
+            let done = poll == Poll::Ready(None);
+            polls.push(poll);
+            if done {
+                break;
+            }
+        });
+        assert_eq!(
+            polls,
+            [
+                Poll::Pending,
+                Poll::Ready(Some(11)),
+                Poll::Pending,
+                Poll::Ready(Some(10)),
+                Poll::Ready(None),
+            ]
+        );
+        assert!(!FOO.is_set());
+    }
+}
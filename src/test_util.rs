@@ -0,0 +1,99 @@
+//! Helpers for stashing and restoring the state of a key in tests.
+
+use std::{fmt, ptr};
+
+use crate::{Frame, ScopedKey};
+
+/// The state of a key on one thread, captured by [`ScopedKey::save_state`].
+pub struct SavedState<T: ?Sized + 'static> {
+    top: *const Frame<T>,
+}
+
+impl<T: ?Sized> fmt::Debug for SavedState<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SavedState").finish_non_exhaustive()
+    }
+}
+
+impl<T: ?Sized> ScopedKey<T> {
+    /// Captures which value this key currently has on this thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// FOO.set(&1, || {
+    ///     let saved = FOO.save_state();
+    ///     let guard = unsafe { FOO.set_guard(&2) };
+    ///     FOO.restore_state(saved);
+    ///     assert_eq!(FOO.get(), 1);
+    ///     drop(guard);
+    /// });
+    /// # }
+    /// ```
+    pub fn save_state(&'static self) -> SavedState<T> {
+        SavedState { top: self.top() }
+    }
+
+    /// Reinstalls a state captured by `save_state`, discarding any scopes
+    /// entered since.
+    ///
+    /// The discarded scopes still run their deferred actions when they end,
+    /// but no longer affect the value of the key.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the scope which was innermost when the
+    /// state was saved has since ended.
+    pub fn restore_state(&'static self, state: SavedState<T>) {
+        let mut cur = self.top();
+        // Safety: frames in the list are alive until they unlink themselves
+        while !ptr::eq(cur, state.top) {
+            match unsafe { cur.as_ref() } {
+                Some(frame) => cur = frame.prev.get(),
+                None => panic!(
+                    "cannot restore the state of scoped thread local variable `{}` after \
+                     the scope it was saved in has ended",
+                    self.display_name()
+                ),
+            }
+        }
+        let _ = self.inner.try_with(|c| c.set(state.top as *const ()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    #[test]
+    fn save_restore() {
+        scoped_thread_local!(static FOO: u32);
+
+        let empty = FOO.save_state();
+        FOO.set(&1, || {
+            let saved = FOO.save_state();
+            FOO.set(&2, || {
+                FOO.restore_state(saved);
+                assert_eq!(FOO.get(), 1);
+                // Scopes entered after restoring nest as usual.
+                FOO.set(&3, || assert_eq!(FOO.get(), 3));
+                assert_eq!(FOO.get(), 1);
+            });
+            assert_eq!(FOO.get(), 1);
+
+            FOO.restore_state(empty);
+            assert!(!FOO.is_set());
+        });
+        assert!(!FOO.is_set());
+
+        let stale = FOO.set(&1, || FOO.save_state());
+        let restore = panic::AssertUnwindSafe(|| FOO.restore_state(stale));
+        assert!(panic::catch_unwind(restore).is_err());
+    }
+}
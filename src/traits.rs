@@ -0,0 +1,76 @@
+//! Traits for writing code which is generic over keys.
+
+use crate::ScopedKey;
+
+/// A key holding a scoped value, abstracting over [`ScopedKey`] so that
+/// helpers like middleware can work with any key handed to them.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate scoped_tls;
+///
+/// use scoped_tls::ScopedContext;
+///
+/// scoped_thread_local!(static USER: str);
+///
+/// fn len_in_scope<C: ScopedContext<Value = str>>(key: &'static C, v: &str) -> usize {
+///     key.set_ctx(v, || key.with_ctx(|v| v.len()))
+/// }
+///
+/// # fn main() {
+/// assert_eq!(len_in_scope(&USER, "alice"), 5);
+/// # }
+/// ```
+pub trait ScopedContext {
+    /// The type of the scoped value.
+    type Value: ?Sized;
+
+    /// Installs `v` for the duration of `f`, like [`ScopedKey::set`].
+    fn set_ctx<R>(&'static self, v: &Self::Value, f: impl FnOnce() -> R) -> R;
+
+    /// Calls `f` with the current value, like [`ScopedKey::with`].
+    ///
+    /// # Panics
+    ///
+    /// Implementations panic if no value is available.
+    fn with_ctx<R>(&'static self, f: impl FnOnce(&Self::Value) -> R) -> R;
+}
+
+impl<T: ?Sized> ScopedContext for ScopedKey<T> {
+    type Value = T;
+
+    fn set_ctx<R>(&'static self, v: &T, f: impl FnOnce() -> R) -> R {
+        self.set(v, f)
+    }
+
+    fn with_ctx<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.with(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Display;
+
+    use crate::ScopedContext;
+
+    fn describe<C>(key: &'static C, v: &C::Value) -> String
+    where
+        C: ScopedContext,
+        C::Value: Display,
+    {
+        key.set_ctx(v, || key.with_ctx(|v| format!("<{}>", v)))
+    }
+
+    #[test]
+    fn generic_over_keys() {
+        scoped_thread_local!(static NAME: str);
+        scoped_thread_local!(static ID: u64);
+
+        assert_eq!(describe(&NAME, "alice"), "<alice>");
+        assert_eq!(describe(&ID, &7), "<7>");
+        assert!(!NAME.is_set() && !ID.is_set());
+    }
+}
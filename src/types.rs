@@ -0,0 +1,715 @@
+//! Helpers for keys holding particular types of values.
+
+use std::{
+    borrow::Borrow,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    hash::{BuildHasher, Hash},
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock, Weak,
+    },
+    thread,
+};
+
+use crate::{lazy::Lazy, ScopedKey};
+
+impl ScopedKey<&'static str> {
+    /// Returns a canonical copy of the current string.
+    ///
+    /// Equal strings interned on any thread return the same `&'static str`,
+    /// so names which are set repeatedly share storage and can be compared by
+    /// address.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static SPAN: &'static str);
+    ///
+    /// # fn main() {
+    /// let a = SPAN.set(&"parse", || SPAN.intern_current());
+    /// let b = SPAN.set(&"parse", || SPAN.intern_current());
+    /// assert!(std::ptr::eq(a, b));
+    /// # }
+    /// ```
+    pub fn intern_current(&'static self) -> &'static str {
+        static INTERNER: Lazy<Mutex<HashSet<&'static str>>> = Lazy::new();
+
+        let s = self.with(|s| *s);
+        let mut interner = INTERNER
+            .get(|| Mutex::new(HashSet::new()))
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match interner.get(s) {
+            Some(interned) => interned,
+            None => {
+                interner.insert(s);
+                s
+            }
+        }
+    }
+}
+
+impl ScopedKey<String> {
+    /// Inserts `base` labeled with the current thread's name for the duration
+    /// of a closure.
+    ///
+    /// The value is `{base}@{thread}`, where unnamed threads are labeled
+    /// `<unnamed>`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static CONTEXT: String);
+    ///
+    /// # fn main() {
+    /// std::thread::Builder::new()
+    ///     .name("worker".into())
+    ///     .spawn(|| {
+    ///         CONTEXT.set_with_thread_name("flush", || {
+    ///             CONTEXT.with(|c| assert_eq!(c, "flush@worker"));
+    ///         })
+    ///     })
+    ///     .unwrap()
+    ///     .join()
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn set_with_thread_name<F, R>(&'static self, base: &str, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let thread = thread::current();
+        let value = format!("{}@{}", base, thread.name().unwrap_or("<unnamed>"));
+        self.set(&value, f)
+    }
+}
+
+impl ScopedKey<Cell<u64>> {
+    /// Advances the scoped xorshift random number generator state and
+    /// returns the next number.
+    ///
+    /// The sequence is fully determined by the seed the cell was `set` with,
+    /// which makes it suitable for reproducible simulations but not for
+    /// anything security related. A seed of zero only ever produces zeros.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::cell::Cell;
+    ///
+    /// scoped_thread_local!(static RNG: Cell<u64>);
+    ///
+    /// # fn main() {
+    /// let roll = || RNG.next_u64() % 6 + 1;
+    /// let a = RNG.set(&Cell::new(42), || (roll(), roll()));
+    /// let b = RNG.set(&Cell::new(42), || (roll(), roll()));
+    /// assert_eq!(a, b);
+    /// # }
+    /// ```
+    pub fn next_u64(&'static self) -> u64 {
+        self.with(|state| {
+            let mut x = state.get();
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            state.set(x);
+            x
+        })
+    }
+
+    /// Applies many updates to the scoped counter at once.
+    ///
+    /// The cell is read once before `f` runs and written once after it
+    /// returns, so `f` can update the plain `u64` in a hot loop. Reads of the
+    /// cell made by `f` itself don't observe the updates yet. If `f` panics
+    /// the cell keeps its previous value.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::cell::Cell;
+    ///
+    /// scoped_thread_local!(static BYTES: Cell<u64>);
+    ///
+    /// # fn main() {
+    /// let total = Cell::new(0);
+    /// BYTES.set(&total, || {
+    ///     BYTES.batch(|bytes| {
+    ///         for chunk in &[3, 4, 5] {
+    ///             *bytes += chunk;
+    ///         }
+    ///     });
+    /// });
+    /// assert_eq!(total.get(), 12);
+    /// # }
+    /// ```
+    pub fn batch<F>(&'static self, f: F)
+    where
+        F: FnOnce(&mut u64),
+    {
+        let mut value = self.with(Cell::get);
+        f(&mut value);
+        self.with(|cell| cell.set(value));
+    }
+
+    /// Raises the scoped high-water mark to `v`, if `v` is higher than it.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::cell::Cell;
+    ///
+    /// scoped_thread_local!(static PEAK: Cell<u64>);
+    ///
+    /// # fn main() {
+    /// PEAK.set(&Cell::new(0), || {
+    ///     PEAK.observe(10);
+    ///     PEAK.observe(4);
+    ///     assert_eq!(PEAK.high_water(), 10);
+    /// });
+    /// # }
+    /// ```
+    pub fn observe(&'static self, v: u64) {
+        self.with(|max| max.set(max.get().max(v)));
+    }
+
+    /// Returns the scoped high-water mark recorded by `observe`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    pub fn high_water(&'static self) -> u64 {
+        self.with(Cell::get)
+    }
+}
+
+impl ScopedKey<AtomicUsize> {
+    /// Adds `n` to the scoped counter, returning the previous count.
+    ///
+    /// Uses `Relaxed` ordering. Since `AtomicUsize` is `Sync`, threads
+    /// spawned from the scope can `set` the same counter, e.g. through an
+    /// `Arc`, and add to it concurrently.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::sync::{atomic::AtomicUsize, Arc};
+    /// use std::thread;
+    ///
+    /// scoped_thread_local!(static HITS: AtomicUsize);
+    ///
+    /// # fn main() {
+    /// let hits = Arc::new(AtomicUsize::new(0));
+    /// HITS.set(&hits, || {
+    ///     HITS.fetch_add(1);
+    ///     let hits = hits.clone();
+    ///     thread::spawn(move || HITS.set(&hits, || HITS.fetch_add(1)))
+    ///         .join()
+    ///         .unwrap();
+    ///     assert_eq!(HITS.load(), 2);
+    /// });
+    /// # }
+    /// ```
+    pub fn fetch_add(&'static self, n: usize) -> usize {
+        self.with(|count| count.fetch_add(n, Ordering::Relaxed))
+    }
+
+    /// Returns the current count of the scoped counter, with `Relaxed`
+    /// ordering.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not previously been called.
+    pub fn load(&'static self) -> usize {
+        self.with(|count| count.load(Ordering::Relaxed))
+    }
+}
+
+impl ScopedKey<f64> {
+    /// Returns the current value, or NaN if there is none.
+    ///
+    /// This suits numeric code which already treats NaN as a missing value,
+    /// and would rather not panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static SCALE: f64);
+    ///
+    /// # fn main() {
+    /// assert!(SCALE.get_or_nan().is_nan());
+    /// SCALE.set(&0.5, || assert_eq!(SCALE.get_or_nan(), 0.5));
+    /// # }
+    /// ```
+    pub fn get_or_nan(&'static self) -> f64 {
+        self.try_with(|v| *v).unwrap_or(f64::NAN)
+    }
+}
+
+impl<U> ScopedKey<RwLock<U>> {
+    /// Gets shared access to the value inside the scoped lock.
+    ///
+    /// This blocks while another thread holds the lock for writing. A
+    /// poisoned lock is accessed anyway.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`, or if
+    /// the current thread already holds the lock for writing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::sync::RwLock;
+    ///
+    /// scoped_thread_local!(static STATE: RwLock<Vec<u32>>);
+    ///
+    /// # fn main() {
+    /// STATE.set(&RwLock::new(vec![1]), || {
+    ///     STATE.with_write(|state| state.push(2));
+    ///     assert_eq!(STATE.with_read(|state| state.len()), 2);
+    /// });
+    /// # }
+    /// ```
+    pub fn with_read<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&U) -> R,
+    {
+        self.with(|lock| f(&lock.read().unwrap_or_else(PoisonError::into_inner)))
+    }
+
+    /// Gets exclusive access to the value inside the scoped lock.
+    ///
+    /// This blocks while any other thread holds the lock. A poisoned lock is
+    /// accessed anyway.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic under the same conditions as `with`, or if
+    /// the current thread already holds the lock.
+    pub fn with_write<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&mut U) -> R,
+    {
+        self.with(|lock| f(&mut lock.write().unwrap_or_else(PoisonError::into_inner)))
+    }
+}
+
+impl<U: ?Sized> ScopedKey<Weak<U>> {
+    /// Attempts to upgrade the scoped weak reference.
+    ///
+    /// Returns `None` if the key is not set, or if the value it refers to has
+    /// already been dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::sync::{Arc, Weak};
+    ///
+    /// scoped_thread_local!(static STATE: Weak<u32>);
+    ///
+    /// # fn main() {
+    /// let state = Arc::new(1);
+    /// STATE.set(&Arc::downgrade(&state), || {
+    ///     assert_eq!(STATE.upgrade().as_deref(), Some(&1));
+    /// });
+    /// assert!(STATE.upgrade().is_none());
+    /// # }
+    /// ```
+    pub fn upgrade(&'static self) -> Option<Arc<U>> {
+        self.try_with(Weak::upgrade).ok().flatten()
+    }
+}
+
+impl<K, V, S> ScopedKey<HashMap<K, V, S>>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Returns a clone of the entry for `key` in the scoped map.
+    ///
+    /// Returns `None` if the map has no such entry, or if the key is not set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// use std::collections::HashMap;
+    ///
+    /// scoped_thread_local!(static CTX: HashMap<String, String>);
+    ///
+    /// # fn main() {
+    /// let mut ctx = HashMap::new();
+    /// ctx.insert(String::from("user"), String::from("alice"));
+    /// CTX.set(&ctx, || {
+    ///     assert_eq!(CTX.get_key("user").as_deref(), Some("alice"));
+    ///     assert!(!CTX.contains("request"));
+    /// });
+    /// assert_eq!(CTX.get_key("user"), None);
+    /// # }
+    /// ```
+    pub fn get_key<Q>(&'static self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.try_with(|map| map.get(key).cloned()).ok().flatten()
+    }
+
+    /// Tests whether the scoped map has an entry for `key`.
+    ///
+    /// Returns `false` if the key is not set.
+    pub fn contains<Q>(&'static self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.is(|map| map.contains_key(key))
+    }
+}
+
+impl<U> ScopedKey<[U]> {
+    /// Returns the range of pointers spanned by the current slice, or `None`
+    /// if there is none.
+    ///
+    /// The slice isn't accessed, so this is a cheap way to check whether a
+    /// pointer points into the scoped buffer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static BUF: [u8]);
+    ///
+    /// # fn main() {
+    /// let buf = [0u8; 16];
+    /// BUF.set(&buf, || {
+    ///     let range = BUF.as_ptr_range().unwrap();
+    ///     assert!(range.contains(&(&buf[4] as *const u8)));
+    /// });
+    /// assert!(BUF.as_ptr_range().is_none());
+    /// # }
+    /// ```
+    pub fn as_ptr_range(&'static self) -> Option<Range<*const U>> {
+        self.try_with(<[U]>::as_ptr_range).ok()
+    }
+}
+
+impl ScopedKey<str> {
+    /// Calls `f` with the current string, or with `default` if there is
+    /// none.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_tls;
+    ///
+    /// scoped_thread_local!(static USER: str);
+    ///
+    /// # fn main() {
+    /// assert_eq!(USER.as_str_or("anonymous", str::len), 9);
+    /// USER.set("alice", || assert_eq!(USER.as_str_or("anonymous", str::len), 5));
+    /// # }
+    /// ```
+    pub fn as_str_or<F, R>(&'static self, default: &str, f: F) -> R
+    where
+        F: FnOnce(&str) -> R,
+    {
+        // `try_with` only fails without calling its closure, so `f` is
+        // still there for the fallback.
+        let mut f = Some(f);
+        if let Ok(r) = self.try_with(|v| f.take().unwrap()(v)) {
+            return r;
+        }
+        f.take().unwrap()(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::HashMap,
+        ptr,
+        sync::{atomic::AtomicUsize, Arc, RwLock, Weak},
+        thread,
+    };
+
+    #[test]
+    fn intern_current() {
+        scoped_thread_local!(static SPAN: &'static str);
+
+        let a: &'static str = Box::leak(String::from("span").into_boxed_str());
+        let b: &'static str = Box::leak(String::from("span").into_boxed_str());
+        assert!(!ptr::eq(a, b));
+
+        let a = SPAN.set(&a, || SPAN.intern_current());
+        let b = thread::spawn(move || SPAN.set(&b, || SPAN.intern_current()))
+            .join()
+            .unwrap();
+        assert!(ptr::eq(a, b));
+        assert_eq!(a, "span");
+    }
+
+    #[test]
+    fn set_with_thread_name() {
+        scoped_thread_local!(static CONTEXT: String);
+
+        let context = |name: Option<&str>| {
+            let mut builder = thread::Builder::new();
+            if let Some(name) = name {
+                builder = builder.name(name.to_string());
+            }
+            builder
+                .spawn(|| CONTEXT.set_with_thread_name("job", || CONTEXT.with(|c| c.clone())))
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        assert_eq!(context(Some("pool-1")), "job@pool-1");
+        assert_eq!(context(None), "job@<unnamed>");
+        assert!(!CONTEXT.is_set());
+    }
+
+    #[test]
+    fn seeded_rng() {
+        scoped_thread_local!(static RNG: Cell<u64>);
+
+        let sequence = |seed| {
+            RNG.set(&Cell::new(seed), || {
+                (0..8).map(|_| RNG.next_u64()).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(sequence(1), sequence(1));
+        assert_ne!(sequence(1), sequence(2));
+
+        // A nested scope has its own state.
+        let expected = sequence(7);
+        let actual = RNG.set(&Cell::new(7), || {
+            let first = RNG.next_u64();
+            RNG.set(&Cell::new(3), || RNG.next_u64());
+            vec![first, RNG.next_u64()]
+        });
+        assert_eq!(actual, expected[..2]);
+    }
+
+    #[test]
+    fn batch() {
+        scoped_thread_local!(static COUNT: Cell<u64>);
+
+        let count = Cell::new(5);
+        COUNT.set(&count, || {
+            COUNT.batch(|n| {
+                for _ in 0..10_000 {
+                    *n += 1;
+                }
+                assert_eq!(COUNT.with(Cell::get), 5);
+            });
+            assert_eq!(COUNT.with(Cell::get), 10_005);
+            let _ = std::panic::catch_unwind(|| {
+                COUNT.batch(|n| {
+                    *n = 0;
+                    panic!();
+                })
+            });
+        });
+        assert_eq!(count.get(), 10_005);
+    }
+
+    #[test]
+    fn high_water() {
+        scoped_thread_local!(static PEAK: Cell<u64>);
+
+        PEAK.set(&Cell::new(0), || {
+            for v in [5, 3, 1, 4, 8, 7].iter() {
+                PEAK.observe(*v);
+            }
+            assert_eq!(PEAK.high_water(), 8);
+        });
+        assert!(std::panic::catch_unwind(|| PEAK.high_water()).is_err());
+    }
+
+    #[test]
+    fn f64_get() {
+        scoped_thread_local!(static SCALE: f64);
+
+        assert!(SCALE.get_or_nan().is_nan());
+        SCALE.set(&1.5, || {
+            assert_eq!(SCALE.get(), 1.5);
+            assert_eq!(SCALE.get_or_nan(), 1.5);
+            SCALE.set(&f64::INFINITY, || {
+                assert_eq!(SCALE.get_or_nan(), f64::INFINITY)
+            });
+        });
+        assert!(SCALE.get_or_nan().is_nan());
+    }
+
+    #[test]
+    fn slice_ptr_range() {
+        scoped_thread_local!(static BUF: [u32]);
+
+        let buf = vec![1u32, 2, 3, 4];
+        let other = 5u32;
+        assert_eq!(BUF.as_ptr_range(), None);
+        BUF.set(&buf, || {
+            let range = BUF.as_ptr_range().unwrap();
+            assert!(range.contains(&(&buf[0] as *const u32)));
+            assert!(range.contains(&(&buf[3] as *const u32)));
+            assert!(!range.contains(&(&other as *const u32)));
+            assert_eq!(BUF.with(|b| b.len()), 4);
+
+            BUF.set(&buf[1..2], || {
+                let range = BUF.as_ptr_range().unwrap();
+                assert!(range.contains(&(&buf[1] as *const u32)));
+                assert!(!range.contains(&(&buf[2] as *const u32)));
+            });
+        });
+    }
+
+    #[test]
+    fn rwlock() {
+        scoped_thread_local!(static STATE: RwLock<u32>);
+
+        let lock = Arc::new(RwLock::new(0));
+        STATE.set(&lock, || {
+            STATE.with_write(|n| *n += 1);
+            STATE.with_read(|n| {
+                assert_eq!(*n, 1);
+                // Readers on other threads aren't blocked by this one.
+                let lock = lock.clone();
+                let seen = thread::spawn(move || STATE.set(&lock, || STATE.with_read(|n| *n)));
+                assert_eq!(seen.join().unwrap(), 1);
+            });
+        });
+
+        let writers = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || STATE.set(&lock, || STATE.with_write(|n| *n += 1)))
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(*lock.read().unwrap(), 5);
+    }
+
+    #[test]
+    fn hashmap() {
+        scoped_thread_local!(static CTX: HashMap<&'static str, u32>);
+
+        assert_eq!(CTX.get_key("a"), None);
+        assert!(!CTX.contains("a"));
+        let mut ctx = HashMap::new();
+        ctx.insert("a", 1);
+        CTX.set(&ctx, || {
+            assert_eq!(CTX.get_key("a"), Some(1));
+            assert!(CTX.contains("a"));
+            assert_eq!(CTX.get_key("b"), None);
+            assert!(!CTX.contains("b"));
+        });
+    }
+
+    #[test]
+    fn weak_upgrade() {
+        scoped_thread_local!(static STATE: Weak<String>);
+
+        assert!(STATE.upgrade().is_none());
+        let state = Arc::new(String::from("live"));
+        let weak = Arc::downgrade(&state);
+        STATE.set(&weak, || {
+            assert!(Arc::ptr_eq(&STATE.upgrade().unwrap(), &state));
+        });
+        drop(state);
+        STATE.set(&weak, || assert!(STATE.upgrade().is_none()));
+    }
+
+    #[test]
+    fn atomic_usize() {
+        scoped_thread_local!(static COUNT: AtomicUsize);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        COUNT.set(&count, || {
+            assert_eq!(COUNT.fetch_add(2), 0);
+            let workers = (0..4)
+                .map(|_| {
+                    let count = count.clone();
+                    thread::spawn(move || COUNT.set(&count, || COUNT.fetch_add(1)))
+                })
+                .collect::<Vec<_>>();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            assert_eq!(COUNT.load(), 6);
+        });
+        assert!(std::panic::catch_unwind(|| COUNT.load()).is_err());
+    }
+
+    #[test]
+    fn as_str_or() {
+        scoped_thread_local!(static USER: str);
+
+        assert_eq!(USER.as_str_or("nobody", str::to_owned), "nobody");
+        USER.set("alice", || {
+            assert_eq!(USER.as_str_or("nobody", str::to_owned), "alice");
+        });
+        USER.set("", || {
+            assert_eq!(USER.as_str_or("nobody", str::to_owned), "");
+        });
+    }
+}